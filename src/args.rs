@@ -1,21 +1,760 @@
-use clap::Parser;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use clap::{Arg, CommandFactory, Parser, Subcommand, ValueSource};
+use docker_registry_cleaner::{docker_config, fmt_bytes, Config};
+use docker_registry_cleaner::docker::{AgeSource, Credentials, DateParseErrors, DeleteMode};
+use docker_registry_cleaner::duration::{parse_date, parse_duration};
+use docker_registry_cleaner::filter::{CosignHandling, VersionDetection, VersionLine};
+use docker_registry_cleaner::report::{DeleteOrder, LogFormat, LogRotation, OutputFormat, Progress};
+use glob::Pattern;
+use regex::Regex;
+use tracing::warn;
+use url::Url;
+
+/// Retentions shorter than this are warned about, as they delete nearly every image
+const SHORT_RETENTION: time::Duration = time::Duration::DAY;
 
 #[derive(Debug, Parser, Clone)]
 pub struct Args {
-    #[clap(long)]
-    pub registry: String,
-    #[clap(long)]
-    pub retention: u32,
-    #[clap(long)]
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+    /// TOML file setting any of these options, e.g. `retention = "30d"`. Defaults to $DRC_CONFIG.
+    /// Options given on the command line take precedence over the file, and options in the section of a registry,
+    /// e.g. `[registries."https://registry.example.com"]`, over those at the top of the file
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Can be given multiple times to clean up several registries with the same options
+    #[clap(long, global = true, value_parser = parse_registry)]
+    pub registry: Vec<String>,
+    /// Images older than this are deleted, e.g. `30d`. A retention of 0 deletes every image which isn't kept otherwise,
+    /// and requires --force
+    #[clap(long, global = true, value_parser = parse_duration)]
+    pub retention: Option<time::Duration>,
+    /// Delete images created before this date, instead of those older than --retention
+    #[clap(long, global = true, value_parser = parse_date, conflicts_with_all = &["retention", "policy-file"])]
+    pub before: Option<time::OffsetDateTime>,
+    /// Only log warnings and errors, and print a one line summary of each registry to stdout when done
+    #[clap(long, global = true, visible_alias = "summary-only", conflicts_with_all = &["debug", "trace", "verbose-http"])]
+    pub quiet: bool,
+    #[clap(long, global = true)]
     pub debug: bool,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub trace: bool,
-    #[clap(long)]
+    #[clap(long, global = true, value_enum, default_value_t = LogFormat::Compact)]
+    pub log_format: LogFormat,
+    /// Also write the logs to this file. Colors are disabled, so they don't end up in the file
+    #[clap(long, global = true)]
+    pub log_file: Option<PathBuf>,
+    #[clap(long, global = true, value_enum, default_value_t = LogRotation::Daily, requires = "log-file")]
+    pub log_rotation: LogRotation,
+    #[clap(long, global = true)]
     pub dry_run: bool,
+    /// During a dry run, ask the registry whether each image could be deleted, and fail for those which couldn't
+    #[clap(long, global = true, requires = "dry-run")]
+    pub dry_run_validate: bool,
+    /// List every tag with its age and size and exit, without applying the retention or deleting anything
+    #[clap(long, global = true)]
+    pub list_only: bool,
+    /// Delete the images listed in this file rather than scanning the registry, e.g. a JSON report of a dry run.
+    /// Either a JSON array of objects with a repository, tag and digest, or an object with such an array in `images`
+    #[clap(long, global = true, conflicts_with = "list-only")]
+    pub delete_from_file: Option<PathBuf>,
+    /// Hold an exclusive lock on this file during the run, so runs can't overlap. Exits if another run holds it
+    #[clap(long, global = true)]
+    pub lock_file: Option<PathBuf>,
+    /// Wait for the other run to release the --lock-file, rather than exiting
+    #[clap(long, global = true, requires = "lock-file")]
+    pub lock_wait: bool,
+    #[clap(long, global = true)]
+    pub username: Option<String>,
+    #[clap(long, global = true, requires = "username")]
+    pub password: Option<String>,
+    #[clap(long, global = true, requires = "username", conflicts_with = "password")]
+    pub password_stdin: bool,
+    /// Number of repositories or tags to request per page when listing them. Large pages mean fewer requests
+    #[clap(long, global = true, default_value_t = 1000)]
+    pub page_size: u32,
+    /// Number of repositories processed at once
+    #[clap(long, global = true, default_value_t = 4)]
+    pub repository_concurrency: usize,
+    /// Number of requests in flight per repository. In total, up to --repository-concurrency times this many requests are in flight.
+    /// On high latency registries, raise --repository-concurrency rather than this to keep the load on each repository low
+    #[clap(long, global = true, visible_alias = "concurrency", default_value_t = 16)]
+    pub request_concurrency: usize,
+    #[clap(long, global = true)]
+    pub insecure_skip_tls_verify: bool,
+    #[clap(long, global = true)]
+    pub ca_cert: Vec<PathBuf>,
+    /// PEM file with a client certificate for registries requiring mutual TLS, including its private key unless --client-key is given.
+    /// Independent of --ca-cert, which only determines which registry certificates are trusted
+    #[clap(long, global = true)]
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of --client-cert
+    #[clap(long, global = true, requires = "client-cert")]
+    pub client_key: Option<PathBuf>,
+    #[clap(long, global = true, value_parser = Pattern::new)]
+    pub keep_tag: Vec<Pattern>,
+    #[clap(long, global = true, overrides_with = "no_keep_latest")]
+    pub keep_latest: bool,
+    #[clap(long, global = true, overrides_with = "keep_latest")]
+    pub no_keep_latest: bool,
+    /// Keep version tags, as recognized by --version-detection, i.e. don't count them as free tags. On by default
+    #[clap(long, global = true, overrides_with = "no_keep_version")]
+    pub keep_version: bool,
+    #[clap(long, global = true, overrides_with = "keep_version")]
+    pub no_keep_version: bool,
+    /// Leave repositories with fewer free tags than this alone. Free tags are those not kept by
+    /// --keep-latest, --keep-version or --keep-tag
+    #[clap(long, global = true, default_value_t = 2)]
+    pub min_free_tags: usize,
+    #[clap(long, global = true)]
+    pub policy_file: Option<PathBuf>,
+    #[clap(long, global = true, default_value_t = 0)]
+    pub keep_last: usize,
+    /// Never delete images created or pushed less than this long ago, whatever the retention or policy says, e.g. `12h`
+    #[clap(long, global = true, value_parser = parse_duration)]
+    pub min_age: Option<time::Duration>,
+    /// Always retain the newest version of each minor version, e.g. 1.2.9 and 1.1.5 but not 1.2.8
+    #[clap(long, global = true)]
+    pub keep_per_minor: bool,
+    /// Always retain the newest version of each major version, e.g. 2.0.1 and 1.2.9 but not 1.1.5
+    #[clap(long, global = true, conflicts_with = "keep-per-minor")]
+    pub keep_per_major: bool,
+    #[clap(long, global = true, value_enum, default_value_t = VersionDetection::Prefix)]
+    pub version_detection: VersionDetection,
+    #[clap(long, global = true, value_parser = Pattern::new)]
+    pub repository: Vec<Pattern>,
+    #[clap(long, global = true, value_parser = Pattern::new)]
+    pub exclude_repository: Vec<Pattern>,
+    /// Only clean up repositories under this path, e.g. `team/project`. Only this part of the catalog is listed,
+    /// and tokens are requested per repository, so they never cover repositories outside of it
+    #[clap(long, global = true, value_parser = parse_namespace)]
+    pub namespace: Option<String>,
+    #[clap(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+    /// Write the JSON report to this file rather than stdout
+    #[clap(long, global = true)]
+    pub output_file: Option<PathBuf>,
+    #[clap(long, global = true, default_value_t = 3)]
+    pub max_retries: u32,
+    #[clap(long, global = true, default_value_t = 500)]
+    pub retry_base_delay_ms: u64,
+    /// Total number of retries across all requests to a registry. Once used up, failing requests fail right away,
+    /// which bounds how long a run against a registry that is down takes
+    #[clap(long, global = true)]
+    pub retry_budget: Option<u64>,
+    #[clap(long, global = true)]
+    pub post_delete_command: Option<String>,
+    /// Shell command asked about each image before it's deleted. It gets the image as JSON on stdin,
+    /// like `{"repository": "app", "digest": "sha256:...", "tags": ["v1"], "age_seconds": 86400, "size_bytes": 1024}`,
+    /// and in the DRC_REPOSITORY, DRC_DIGEST, DRC_TAGS and DRC_AGE_SECONDS environment variables.
+    /// Tags sharing a manifest are asked about together. Exit with 0 to delete the image, or 1 to keep it.
+    /// Any other exit code is counted as a failure and keeps the image
+    #[clap(long, global = true)]
+    pub filter_command: Option<String>,
+    /// Maximum number of filter commands running at once
+    #[clap(long, global = true, default_value_t = 4)]
+    pub filter_command_concurrency: usize,
+    #[clap(long, global = true, default_value_t = 30)]
+    pub request_timeout_secs: u64,
+    /// Timeout of the requests listing the catalog and tags, which can take much longer than other requests on large registries.
+    /// Defaults to --request-timeout-secs
+    #[clap(long, global = true)]
+    pub catalog_timeout_secs: Option<u64>,
+    /// Only clean up this many repositories of the catalog per run, before applying the repository filters
+    #[clap(long, global = true, conflicts_with_all = &["only-repository", "delete-from-file"])]
+    pub catalog_limit: Option<usize>,
+    /// Keep the position in the catalog in this file, so each run continues with the next --catalog-limit repositories.
+    /// Dry runs and interrupted runs don't move it
+    #[clap(long, global = true, requires = "catalog-limit")]
+    pub catalog_cursor_file: Option<PathBuf>,
+    #[clap(long, global = true, default_value_t = 10)]
+    pub connect_timeout_secs: u64,
+    /// Give up on a repository if planning its deletions takes longer than this, moving on to the others
+    #[clap(long, global = true)]
+    pub repo_timeout_secs: Option<u64>,
+    #[clap(long, global = true)]
+    pub fail_fast: bool,
+    #[clap(long, global = true, value_parser = Regex::new)]
+    pub protect_tag: Vec<Regex>,
+    /// Only consider tags matching this glob for deletion, leaving every other tag untouched. Can be given multiple times
+    #[clap(long, global = true, value_parser = Pattern::new)]
+    pub only_tags_matching: Vec<Pattern>,
+    /// Only delete images of at least this many bytes, leaving smaller images alone however old they are
+    #[clap(long, global = true)]
+    pub min_size: Option<u64>,
+    /// Warn about repositories with more than this many tags, which usually means a CI pipeline tags every build
+    #[clap(long, global = true)]
+    pub warn_tag_count_threshold: Option<usize>,
+    #[clap(long, global = true, default_value_t = 4)]
+    pub delete_concurrency: usize,
+    /// The order images are deleted in. Largest first frees the most space if the run is interrupted
+    #[clap(long, global = true, value_enum, default_value_t = DeleteOrder::OldestFirst)]
+    pub delete_order: DeleteOrder,
+    #[clap(long, global = true)]
+    pub metrics_file: Option<PathBuf>,
+    #[clap(long, global = true, default_value_t = 0)]
+    pub min_tags_to_keep: usize,
+    /// Allow deleting every tag of a repository. Otherwise the newest tag is kept, even if it's older than the retention
+    #[clap(long, global = true)]
+    pub allow_empty_repository: bool,
+    #[clap(long, global = true)]
+    pub webhook_url: Option<String>,
+    #[clap(long, global = true, value_enum, default_value_t = AgeSource::Created)]
+    pub age_source: AgeSource,
+    #[clap(long, global = true, value_enum, default_value_t = DeleteMode::Manifest)]
+    pub delete_mode: DeleteMode,
+    /// What to do with images whose creation date can't be parsed
+    #[clap(long, global = true, value_enum, default_value_t = DateParseErrors::Skip)]
+    pub on_parse_error: DateParseErrors,
+    #[clap(long, global = true)]
+    pub allow_shared_digest_delete: bool,
+    #[clap(long, global = true, value_enum, default_value_t = Progress::Auto)]
+    pub progress: Progress,
+    #[clap(long, global = true)]
+    pub rate_limit: Option<f64>,
+    #[clap(long, global = true, conflicts_with_all = &["repository", "exclude-repository", "namespace"])]
+    pub only_repository: Option<String>,
+    #[clap(long, global = true, conflicts_with = "username")]
+    pub ecr_region: Option<String>,
+    #[clap(long, global = true, conflicts_with_all = &["username", "ecr-region"])]
+    pub use_docker_config: bool,
+    /// Log every request to the registry and its response. This implies --trace
+    #[clap(long, global = true)]
+    pub verbose_http: bool,
+    #[clap(long, global = true, value_enum, default_value_t = CosignHandling::Link)]
+    pub handle_cosign: CosignHandling,
+    #[clap(long, global = true)]
+    pub delete_referrers: bool,
+    /// Only consider these platforms of multi-arch images, e.g. `linux/amd64` or `linux/arm/v7`. Can be given multiple times
+    #[clap(long, global = true, value_parser = parse_platform)]
+    pub platform: Vec<String>,
+    /// Delete without asking for confirmation
+    #[clap(long, global = true, short = 'y')]
+    pub yes: bool,
+    /// Like --yes, but also delete when more than --max-delete-fraction of the tags would be deleted
+    #[clap(long, global = true)]
+    pub force: bool,
+    #[clap(long, global = true)]
+    pub audit_log: Option<PathBuf>,
+    #[clap(long, global = true)]
+    pub max_deletions: Option<usize>,
+    /// Also delete untagged manifests older than the retention. Only supported by registries which list them, like Google's
+    #[clap(long, global = true)]
+    pub delete_untagged: bool,
+    #[clap(long, global = true, value_parser = parse_fraction)]
+    pub max_delete_fraction: Option<f64>,
+    /// Proxy to connect through, overriding the HTTP_PROXY, HTTPS_PROXY and NO_PROXY environment variables
+    #[clap(long, global = true)]
+    pub proxy: Option<String>,
+    #[clap(long, global = true, requires = "proxy")]
+    pub no_proxy: Option<String>,
+    #[clap(long, global = true)]
+    pub cache_file: Option<PathBuf>,
+    /// Mirror of --registry to list and inspect images on, to take load off the primary. Deletions still go to --registry
+    #[clap(long, global = true, value_parser = parse_registry)]
+    pub read_registry: Option<String>,
+    /// Don't color the output. Also disabled by setting $NO_COLOR, or when stderr is not a terminal
+    #[clap(long, global = true)]
+    pub no_color: bool,
+    /// `Accept` header to send when fetching manifests, instead of every supported media type.
+    /// Can be given multiple times, the values are sent as is
+    #[clap(long, global = true)]
+    pub manifest_accept: Vec<String>,
+    /// Ignore images dated in the future, rather than only warning about them
+    #[clap(long, global = true)]
+    pub skip_future_dated: bool,
+    /// Ramp up from one request in flight to the maximum over this long, e.g. `10s`. Defaults to 5s when no duration is given
+    #[clap(long, global = true, value_parser = parse_duration, min_values = 0, default_missing_value = "5s")]
+    pub warmup: Option<time::Duration>,
+    /// Reject manifests and blobs larger than this many bytes. Defaults to 32 MiB
+    #[clap(long, global = true, default_value_t = 32 * 1024 * 1024)]
+    pub max_response_bytes: u64,
+    /// The options of the registries with their own section in the config file, see [ConfigFile]
+    #[clap(skip)]
+    pub registry_args: HashMap<String, Args>,
+}
+
+/// Instead of cleaning up the registry
+#[derive(Debug, Subcommand, Clone)]
+pub enum Command {
+    /// Check that the registry can be reached and the credentials work, and list a few repositories. Nothing is deleted
+    Check,
 }
 
 impl Args {
     pub fn new() -> Self {
-        Self::parse()
+        let args = std::env::args().collect::<Vec<_>>();
+        let config = find_config_path(&args).or_else(|| std::env::var_os("DRC_CONFIG").map(PathBuf::from));
+
+        let parsed = config
+            .map(|path| ConfigFile::load(&path))
+            .transpose()
+            .and_then(|config| Self::parse_with_config(args, config.as_ref()));
+        match parsed {
+            Ok(args) => args,
+            Err(e) => {
+                // Mirror clap's behaviour for invalid arguments
+                eprintln!("error: {e:#}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// Parse the command line arguments on top of the options from the config file, if any
+    fn parse_with_config(args: Vec<String>, config: Option<&ConfigFile>) -> Result<Self> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(Self::parse_from(args)),
+        };
+
+        let mut parsed = Self::parse_from(config.with_options(args.clone(), None)?);
+        for registry in &parsed.registry {
+            if config.sections.contains_key(registry) {
+                let registry_args = Self::parse_from(config.with_options(args.clone(), Some(registry))?);
+                parsed.registry_args.insert(registry.clone(), registry_args);
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Whether the output is colored. The output is logged, so this depends on stderr rather than stdout
+    pub fn color(&self) -> bool {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty());
+        // Escape codes would end up in the JSON strings
+        !self.no_color && !no_color && self.log_format != LogFormat::Json && self.log_file.is_none() && std::io::stderr().is_terminal()
+    }
+
+    /// The configuration of the run against each registry
+    pub fn into_configs(self) -> Result<Vec<Config>> {
+        // Not enforced by clap, as the options are global and only some of them are required for the subcommands
+        if self.registry.is_empty() {
+            bail!("--registry is required");
+        }
+        // The text output is the log, which goes to stderr
+        if self.output_file.is_some() && self.output != OutputFormat::Json {
+            bail!("--output-file can only be used with --output json");
+        }
+
+        // These files would be overwritten by each registry in turn
+        if self.registry.len() > 1 {
+            let per_registry_files = [("--cache-file", self.cache_file.is_some()), ("--audit-log", self.audit_log.is_some()), ("--metrics-file", self.metrics_file.is_some()), ("--read-registry", self.read_registry.is_some()),
+                ("--catalog-cursor-file", self.catalog_cursor_file.is_some())];
+            if let Some((flag, _)) = per_registry_files.iter().find(|(_, set)| *set) {
+                bail!("{flag} can only be used with a single --registry");
+            }
+        }
+
+        // Shared by every registry, so the password is only asked for once
+        let credentials = read_credentials(&self)?;
+        let mut configs = Vec::with_capacity(self.registry.len());
+        for registry in &self.registry {
+            let args = self.registry_args.get(registry).unwrap_or(&self);
+            args.check_retention()?;
+
+            let credentials = match args.username != self.username || args.password != self.password {
+                true => read_credentials(args)?,
+                false => credentials.clone(),
+            };
+            let credentials = match credentials {
+                None if args.use_docker_config => docker_config::find_credentials(registry)?,
+                credentials => credentials,
+            };
+
+            configs.push(args.clone().into_config(registry.clone(), credentials)?);
+        }
+        Ok(configs)
+    }
+
+    /// Check the options deciding which images are deleted, which can differ per registry
+    fn check_retention(&self) -> Result<()> {
+        if self.command.is_none() && self.retention.is_none() && self.before.is_none() && !self.list_only && self.delete_from_file.is_none() {
+            bail!("--retention is required, unless --before, --list-only or --delete-from-file is given");
+        }
+
+        // Typically a typo or an unset variable, but it would delete every tag which isn't kept otherwise
+        if let Some(retention) = self.retention {
+            if retention.is_zero() && !self.force && !self.dry_run && !self.list_only {
+                bail!("--retention 0 deletes every image which isn't kept by another option. Pass --force if this is intended, or use --dry-run to see what would be deleted");
+            }
+            if retention < SHORT_RETENTION {
+                warn!("--retention is only {retention}, nearly every image will be deleted");
+            }
+        }
+        Ok(())
+    }
+
+    fn into_config(self, registry: String, credentials: Option<Credentials>) -> Result<Config> {
+        let color = self.color();
+
+        Ok(Config {
+            registry,
+            // Unused when deleting by date
+            retention: self.retention.unwrap_or(time::Duration::ZERO),
+            before: self.before,
+            dry_run: self.dry_run,
+            dry_run_validate: self.dry_run_validate,
+            credentials,
+            page_size: Some(self.page_size),
+            repository_concurrency: self.repository_concurrency,
+            request_concurrency: self.request_concurrency,
+            insecure_skip_tls_verify: self.insecure_skip_tls_verify,
+            ca_cert: self.ca_cert,
+            client_cert: self.client_cert,
+            client_key: self.client_key,
+            keep_tag: self.keep_tag,
+            keep_latest: !self.no_keep_latest,
+            keep_version: !self.no_keep_version,
+            min_free_tags: self.min_free_tags,
+            policy_file: self.policy_file,
+            keep_last: self.keep_last,
+            min_age: self.min_age,
+            keep_per_version: match (self.keep_per_minor, self.keep_per_major) {
+                (true, _) => Some(VersionLine::Minor),
+                (_, true) => Some(VersionLine::Major),
+                _ => None,
+            },
+            version_detection: self.version_detection,
+            repository: self.repository,
+            exclude_repository: self.exclude_repository,
+            namespace: self.namespace,
+            output: self.output,
+            max_retries: self.max_retries,
+            retry_base_delay: std::time::Duration::from_millis(self.retry_base_delay_ms),
+            retry_budget: self.retry_budget,
+            post_delete_command: self.post_delete_command,
+            filter_command: self.filter_command,
+            filter_command_concurrency: self.filter_command_concurrency,
+            request_timeout: std::time::Duration::from_secs(self.request_timeout_secs),
+            catalog_timeout: self.catalog_timeout_secs.map(std::time::Duration::from_secs),
+            catalog_limit: self.catalog_limit,
+            catalog_cursor_file: self.catalog_cursor_file,
+            connect_timeout: std::time::Duration::from_secs(self.connect_timeout_secs),
+            repository_timeout: self.repo_timeout_secs.map(std::time::Duration::from_secs),
+            fail_fast: self.fail_fast,
+            protect_tag: self.protect_tag,
+            only_tags_matching: self.only_tags_matching,
+            min_size: self.min_size,
+            warn_tag_count_threshold: self.warn_tag_count_threshold,
+            delete_concurrency: self.delete_concurrency,
+            delete_order: self.delete_order,
+            metrics_file: self.metrics_file,
+            min_tags_to_keep: self.min_tags_to_keep,
+            allow_empty_repository: self.allow_empty_repository,
+            webhook_url: self.webhook_url,
+            age_source: self.age_source,
+            delete_mode: self.delete_mode,
+            on_parse_error: self.on_parse_error,
+            allow_shared_digest_delete: self.allow_shared_digest_delete,
+            progress: self.progress,
+            rate_limit: self.rate_limit,
+            only_repository: self.only_repository,
+            ecr_region: self.ecr_region,
+            verbose_http: self.verbose_http,
+            handle_cosign: self.handle_cosign,
+            delete_referrers: self.delete_referrers,
+            platforms: self.platform,
+            audit_log: self.audit_log,
+            max_deletions: self.max_deletions,
+            color,
+            skip_future_dated: self.skip_future_dated,
+            manifest_accept: self.manifest_accept,
+            warmup: self.warmup.map(|x| x.unsigned_abs()),
+            max_response_bytes: self.max_response_bytes,
+            // Set up by the caller
+            shutdown: None,
+            delete_untagged: self.delete_untagged,
+            max_delete_fraction: self.max_delete_fraction.filter(|_| !self.force),
+            proxy: self.proxy,
+            no_proxy: self.no_proxy,
+            cache_file: self.cache_file,
+            delete_from_file: self.delete_from_file,
+            read_registry: self.read_registry,
+            confirm: (!self.yes && !self.force).then_some(confirm_deletion as fn(usize, u64) -> Result<bool>),
+        })
     }
-}
\ No newline at end of file
+}
+
+fn read_credentials(args: &Args) -> Result<Option<Credentials>> {
+    let username = match &args.username {
+        Some(username) => username.clone(),
+        // Looked up for each registry
+        None => return Ok(None),
+    };
+
+    let password = match &args.password {
+        Some(password) => password.clone(),
+        // Prompt when a user is sitting at the terminal, otherwise the password is piped in
+        None if !args.password_stdin && std::io::stdin().is_terminal() => rpassword::prompt_password(format!("Password for {username}: "))?,
+        None => {
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            password.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+
+    Ok(Some(Credentials { username, password }))
+}
+
+fn parse_fraction(input: &str) -> Result<f64> {
+    let fraction: f64 = input.parse()?;
+    if !(0.0..=1.0).contains(&fraction) {
+        bail!("expected a fraction between 0.0 and 1.0");
+    }
+    Ok(fraction)
+}
+
+/// Check the registry is an HTTP(S) URL, and strip trailing slashes so it can be compared and joined consistently
+fn parse_registry(input: &str) -> Result<String> {
+    let url = Url::parse(input).with_context(|| format!("Invalid registry URL {input}, it should look like https://registry.example.com"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Invalid registry URL {input}, it should start with http:// or https://");
+    }
+    if url.host().is_none() || url.query().is_some() || url.fragment().is_some() {
+        bail!("Invalid registry URL {input}, it should look like https://registry.example.com");
+    }
+    Ok(input.trim_end_matches('/').to_string())
+}
+
+/// Normalize a namespace to end in a single `/`, so `team` doesn't match `team-b/app`
+fn parse_namespace(input: &str) -> Result<String> {
+    let namespace = input.trim_matches('/');
+    if namespace.is_empty() {
+        bail!("Invalid namespace {input}, expected a path like team/project");
+    }
+    Ok(format!("{namespace}/"))
+}
+
+fn parse_platform(input: &str) -> Result<String> {
+    let parts = input.split('/').collect::<Vec<_>>();
+    if !(2..=3).contains(&parts.len()) || parts.iter().any(|x| x.is_empty()) {
+        bail!("Invalid platform {input}, expected os/architecture or os/architecture/variant");
+    }
+    Ok(input.to_string())
+}
+
+fn confirm_deletion(images: usize, bytes: u64) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        bail!("Refusing to delete {images} images without confirmation, pass --yes to delete without a prompt");
+    }
+
+    eprint!("Delete {images} images ({})? [y/N] ", fmt_bytes(bytes));
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn find_config_path(args: &[String]) -> Option<PathBuf> {
+    args.iter()
+        .enumerate()
+        .find_map(|(idx, arg)| match arg.strip_prefix("--config") {
+            Some("") => args.get(idx + 1).map(PathBuf::from),
+            Some(value) => value.strip_prefix('=').map(PathBuf::from),
+            None => None,
+        })
+}
+
+/// A TOML file setting options, e.g. `retention = "30d"`. Options for a single registry go in its own section,
+/// e.g. `[registries."https://registry.example.com"]`, and take precedence over those at the top level
+struct ConfigFile {
+    path: PathBuf,
+    options: toml::Table,
+    /// Keyed by the registry URL, normalized like `--registry`
+    sections: HashMap<String, toml::Table>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(path, &contents)
+    }
+
+    fn parse(path: &Path, contents: &str) -> Result<Self> {
+        let mut options: toml::Table = toml::from_str(contents).with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+        let mut sections = HashMap::new();
+        match options.remove("registries") {
+            Some(toml::Value::Table(registries)) => for (registry, section) in registries {
+                let section = match section {
+                    toml::Value::Table(section) => section,
+                    _ => bail!("Expected a section for registry '{registry}' in config file {}", path.display()),
+                };
+                if let Some(key) = ["registry", "registries"].iter().find(|x| section.contains_key(**x)) {
+                    bail!("Option '{key}' can't be set for a single registry in config file {}", path.display());
+                }
+                sections.insert(parse_registry(&registry)?, section);
+            },
+            Some(_) => bail!("Expected a section per registry in 'registries' in config file {}", path.display()),
+            None => {}
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            options,
+            sections,
+        })
+    }
+
+    /// Insert the options from the file before the command line arguments, including those in the section of `registry`.
+    /// Options which are also given on the command line, or conflict with one that is, are skipped, so the command line takes precedence
+    fn with_options(&self, args: Vec<String>, registry: Option<&str>) -> Result<Vec<String>> {
+        let mut options = self.options.clone();
+        if let Some(section) = registry.and_then(|x| self.sections.get(x)) {
+            options.extend(section.clone());
+        }
+
+        let path = self.path.display();
+        let mut command = Args::command();
+        // Drops the --version flag clap adds, which we don't have
+        command.build();
+        // The command line is parsed on its own to tell which options it gives, whether by short flag, long flag or alias.
+        // Errors are left for parsing the full arguments to report
+        let matches = command.clone().ignore_errors(true).try_get_matches_from(&args).ok();
+        let given = command.get_arguments()
+            .filter(|arg| matches.as_ref().is_some_and(|x| x.value_source(arg.get_id()) == Some(ValueSource::CommandLine)))
+            .collect::<Vec<_>>();
+        let conflicts = |a: &Arg, b: &Arg| command.get_arg_conflicts_with(a).iter().any(|x| x.get_id() == b.get_id());
+
+        let mut file_args = Vec::new();
+        for (key, value) in options {
+            let name = key.replace('_', "-");
+            // Aliases are accepted too, so old config files keep working
+            let arg = command.get_arguments().find(|x| x.get_long_and_visible_aliases().is_some_and(|x| x.contains(&name.as_str())));
+            let arg = match arg {
+                Some(arg) if name != "config" => arg,
+                _ => bail!("Unknown option '{key}' in config file {path}"),
+            };
+
+            let flag = format!("--{name}");
+            // The command line takes precedence, also over options it can't be combined with, like --retention when --before is given
+            if given.iter().any(|x| x.get_id() == arg.get_id() || conflicts(x, arg) || conflicts(arg, x)) {
+                continue;
+            }
+
+            let values = match value {
+                toml::Value::Array(values) => values,
+                value => vec![value],
+            };
+
+            for value in values {
+                match value {
+                    toml::Value::Boolean(true) => file_args.push(flag.clone()),
+                    // Flags like --keep-latest have a --no- counterpart to turn them off
+                    toml::Value::Boolean(false) => if command.get_arguments().any(|x| x.get_long() == Some(format!("no-{name}").as_str())) {
+                        file_args.push(format!("--no-{name}"));
+                    },
+                    toml::Value::String(value) => file_args.push(format!("{flag}={value}")),
+                    toml::Value::Integer(value) => file_args.push(format!("{flag}={value}")),
+                    toml::Value::Float(value) => file_args.push(format!("{flag}={value}")),
+                    _ => bail!("Unsupported value for option '{key}' in config file {path}"),
+                }
+            }
+        }
+
+        let mut args = args.into_iter();
+        Ok(args.next().into_iter()
+            .chain(file_args)
+            .chain(args)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str, args: &[&str]) -> Args {
+        let config = ConfigFile::parse(Path::new("drc.toml"), config).unwrap();
+        let args = std::iter::once("drc").chain(args.iter().copied()).map(String::from).collect();
+        Args::parse_with_config(args, Some(&config)).unwrap()
+    }
+
+    #[test]
+    fn command_line_beats_config_file() {
+        let args = parse(r#"
+            registry = "https://registry.example.com"
+            retention = "30d"
+            keep_tag = ["stable"]
+        "#, &["--retention", "7d", "--keep-tag=release-*"]);
+
+        assert_eq!(args.retention, Some(time::Duration::days(7)));
+        assert_eq!(args.keep_tag, [Pattern::new("release-*").unwrap()]);
+        assert_eq!(args.registry, ["https://registry.example.com"]);
+    }
+
+    #[test]
+    fn command_line_beats_conflicting_config_file_option() {
+        let args = parse(r#"
+            retention = "30d"
+            policy_file = "policy.yaml"
+            summary_only = true
+        "#, &["--before", "2024-01-01", "--debug"]);
+
+        assert_eq!(args.before.map(|x| x.unix_timestamp()), Some(1704067200));
+        assert_eq!(args.retention, None);
+        assert_eq!(args.policy_file, None);
+        assert!(args.debug);
+        assert!(!args.quiet);
+    }
+
+    #[test]
+    fn command_line_short_flag_and_alias() {
+        let args = parse(r#"
+            yes = true
+            quiet = true
+            page_size = 50
+        "#, &["-y", "--summary-only", "check", "--page-size=20"]);
+
+        // Given once, rather than by both the file and the command line
+        assert!(args.yes);
+        assert!(args.quiet);
+        assert_eq!(args.page_size, 20);
+    }
+
+    #[test]
+    fn config_file_beats_default() {
+        let args = parse(r#"
+            retention = "30d"
+            page_size = 50
+            dry_run = true
+            keep_latest = false
+        "#, &["--registry", "https://registry.example.com"]);
+
+        assert_eq!(args.retention, Some(time::Duration::days(30)));
+        assert_eq!(args.page_size, 50);
+        assert!(args.dry_run);
+        assert!(args.no_keep_latest);
+        // Not in the file, so still the default
+        assert_eq!(args.repository_concurrency, 4);
+    }
+
+    #[test]
+    fn registry_section_beats_top_level() {
+        let args = parse(r#"
+            registry = ["https://a.example.com", "https://b.example.com"]
+            retention = "30d"
+            page_size = 50
+            [registries."https://a.example.com/"]
+            retention = "90d"
+            page_size = 10
+        "#, &["--page-size", "20"]);
+
+        let configs = args.into_configs().unwrap();
+        let (a, b) = (&configs[0], &configs[1]);
+        assert_eq!(a.registry, "https://a.example.com");
+        assert_eq!(a.retention, time::Duration::days(90));
+        assert_eq!(b.retention, time::Duration::days(30));
+        // The command line still beats the section
+        assert_eq!(a.page_size, Some(20));
+        assert_eq!(b.page_size, Some(20));
+    }
+
+    #[test]
+    fn unknown_option_in_config_file() {
+        let config = ConfigFile::parse(Path::new("drc.toml"), "retension = \"30d\"").unwrap();
+        let error = config.with_options(vec!["drc".to_string()], None).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown option 'retension' in config file drc.toml");
+    }
+}
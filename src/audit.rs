@@ -0,0 +1,105 @@
+use std::path::Path;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use crate::Config;
+use crate::filter::VersionLine;
+use crate::report::ImageReport;
+
+/// Record of what a run is about to delete, written before the first deletion
+#[derive(Debug, Serialize)]
+struct AuditLog<'a> {
+    timestamp: String,
+    dry_run: bool,
+    /// If set, the run was interrupted and only the listed images were deleted
+    interrupted: bool,
+    config: AuditConfig<'a>,
+    images: Vec<AuditEntry<'a>>,
+}
+
+/// The options deciding what is deleted
+#[derive(Debug, Serialize)]
+struct AuditConfig<'a> {
+    registry: &'a str,
+    retention_seconds: i64,
+    before: Option<String>,
+    policy_file: Option<&'a Path>,
+    keep_last: usize,
+    min_age_seconds: Option<i64>,
+    keep_per_version: Option<VersionLine>,
+    keep_latest: bool,
+    keep_version: bool,
+    min_free_tags: usize,
+    keep_tag: Vec<&'a str>,
+    protect_tag: Vec<&'a str>,
+    only_tags_matching: Vec<&'a str>,
+    min_size: Option<u64>,
+    repository: Vec<&'a str>,
+    exclude_repository: Vec<&'a str>,
+    namespace: Option<&'a str>,
+    only_repository: Option<&'a str>,
+    min_tags_to_keep: usize,
+    allow_empty_repository: bool,
+    delete_mode: String,
+    delete_untagged: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    repository: &'a str,
+    tag: Option<&'a str>,
+    digest: &'a str,
+    size_bytes: u64,
+    /// When the image was created, or pushed depending on the age source
+    date: String,
+}
+
+/// Write the images about to be deleted to `path`, along with the configuration that selected them.
+/// If the run is `interrupted`, this is called again with only the images which were deleted
+pub fn write_audit_log(path: &Path, config: &Config, images: &[ImageReport], interrupted: bool) -> Result<()> {
+    let now = OffsetDateTime::now_utc();
+    let log = AuditLog {
+        timestamp: now.format(&Rfc3339)?,
+        dry_run: config.dry_run,
+        interrupted,
+        config: AuditConfig {
+            registry: &config.registry,
+            retention_seconds: config.retention.whole_seconds(),
+            before: config.before.map(|x| x.format(&Rfc3339)).transpose()?,
+            policy_file: config.policy_file.as_deref(),
+            keep_last: config.keep_last,
+            min_age_seconds: config.min_age.map(|x| x.whole_seconds()),
+            keep_per_version: config.keep_per_version,
+            keep_latest: config.keep_latest,
+            keep_version: config.keep_version,
+            min_free_tags: config.min_free_tags,
+            keep_tag: config.keep_tag.iter().map(|x| x.as_str()).collect(),
+            protect_tag: config.protect_tag.iter().map(|x| x.as_str()).collect(),
+            only_tags_matching: config.only_tags_matching.iter().map(|x| x.as_str()).collect(),
+            min_size: config.min_size,
+            repository: config.repository.iter().map(|x| x.as_str()).collect(),
+            exclude_repository: config.exclude_repository.iter().map(|x| x.as_str()).collect(),
+            namespace: config.namespace.as_deref(),
+            only_repository: config.only_repository.as_deref(),
+            min_tags_to_keep: config.min_tags_to_keep,
+            allow_empty_repository: config.allow_empty_repository,
+            delete_mode: config.delete_mode.to_possible_value().map_or_else(String::new, |x| x.get_name().to_string()),
+            delete_untagged: config.delete_untagged,
+        },
+        images: images.iter()
+            .map(|x| Ok(AuditEntry {
+                repository: &x.repository,
+                tag: x.tag.as_deref(),
+                digest: &x.digest,
+                size_bytes: x.size_bytes,
+                date: OffsetDateTime::from_unix_timestamp(x.date)?.format(&Rfc3339)?,
+            }))
+            .collect::<Result<_>>()?,
+    };
+
+    let json = serde_json::to_string_pretty(&log)?;
+    std::fs::write(path, json).with_context(|| format!("Failed to write audit log {}", path.display()))?;
+    Ok(())
+}
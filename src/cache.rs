@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use crate::docker::AgeSource;
+
+/// Digests and dates of tags seen in earlier runs, so unchanged tags don't have to be fetched again
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    /// The age source the dates were determined with. Dates from another source are useless
+    age_source: String,
+    /// Keyed by `repository:tag`
+    entries: HashMap<String, CacheEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Digest of the manifest the tag pointed to
+    pub digest: String,
+    pub date: i64,
+    pub size: u64,
+    /// When the manifest was pushed, needed for `--min-age`. Missing from caches written by older versions
+    #[serde(default)]
+    pub pushed: Option<i64>,
+}
+
+impl Cache {
+    /// Load the cache, starting with an empty one if the file doesn't exist or was written with another age source
+    pub fn load(path: &Path, age_source: AgeSource) -> Result<Self> {
+        let age_source = age_source.to_possible_value().map_or_else(String::new, |x| x.get_name().to_string());
+        let empty = Self {
+            age_source: age_source.clone(),
+            entries: HashMap::new(),
+        };
+
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("Cache file {} doesn't exist yet", path.display());
+                return Ok(empty);
+            },
+            Err(e) => return Err(e).with_context(|| format!("Failed to read cache file {}", path.display())),
+        };
+
+        // The cache can always be rebuilt, so a broken one isn't worth failing the run over
+        let cache: Self = match serde_json::from_str(&contents) {
+            Ok(cache) => cache,
+            Err(e) => {
+                warn!("Ignoring invalid cache file {}: {e}", path.display());
+                return Ok(empty);
+            }
+        };
+
+        if cache.age_source != age_source {
+            debug!("Ignoring cache file {}, it was written with age source {}", path.display(), cache.age_source);
+            return Ok(empty);
+        }
+
+        Ok(cache)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self)?;
+        std::fs::write(path, json).with_context(|| format!("Failed to write cache file {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn get(&self, repository: &str, tag: &str) -> Option<&CacheEntry> {
+        self.entries.get(&key(repository, tag))
+    }
+
+    pub fn insert(&mut self, repository: &str, tag: &str, entry: CacheEntry) {
+        self.entries.insert(key(repository, tag), entry);
+    }
+
+    pub fn remove(&mut self, repository: &str, tag: &str) {
+        self.entries.remove(&key(repository, tag));
+    }
+}
+
+fn key(repository: &str, tag: &str) -> String {
+    format!("{repository}:{tag}")
+}
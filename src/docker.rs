@@ -1,119 +1,1754 @@
-use lazy_static::lazy_static;
-use reqwest::{Client, Response};
-use anyhow::Result;
-use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use reqwest::{Client, Method, Request, RequestBuilder, Response, StatusCode, Url};
+use reqwest::header::{ALLOW, AUTHORIZATION, CONTENT_TYPE, HeaderValue, LAST_MODIFIED, LINK, RETRY_AFTER, WWW_AUTHENTICATE};
+use crate::error::{CheckStatus, DrcError, Result};
+use clap::ValueEnum;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 use time::OffsetDateTime;
-use tracing::{error_span, info, instrument};
-use tracing_subscriber::Registry;
+use tokio::sync::{mpsc, Mutex, Semaphore, SemaphorePermit};
+use tracing::{debug, instrument, trace, warn};
+use crate::ecr::EcrLogin;
 
-lazy_static! {
-    static ref CLIENT: Client = Client::new();
+/// Tokens are considered expired this long before the registry says they are,
+/// so we don't send a token that expires while the request is in flight
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+/// Expiry to assume when the token server doesn't return `expires_in`, as per the token spec
+const DEFAULT_TOKEN_EXPIRY: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct Token {
+    value: String,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegistryOptions {
+    /// Value of the `n` query parameter for paginated listings, or the registry's default if `None`
+    pub page_size: Option<u32>,
+    /// How often a request failing with a transient error is retried
+    pub max_retries: u32,
+    /// Delay before the first retry, which is doubled on every subsequent retry
+    pub retry_base_delay: Duration,
+    /// Total number of retries across all requests, after which failing requests are no longer retried
+    pub retry_budget: Option<u64>,
+    /// Timeout of the requests listing the catalog and tags, replacing the client's timeout
+    pub catalog_timeout: Option<Duration>,
+    /// Which timestamp determines the age of an image
+    pub age_source: AgeSource,
+    /// What a deletion removes from the registry
+    pub delete_mode: DeleteMode,
+    /// Maximum number of requests per second sent to the registry, or unlimited if `None`
+    pub rate_limit: Option<f64>,
+    /// The AWS region of an ECR registry. When set, credentials are obtained from ECR rather than given
+    pub ecr_region: Option<String>,
+    /// Log every request and response at trace level
+    pub verbose_http: bool,
+    /// Also delete the artifacts referring to a deleted manifest, see [list_referrers]
+    pub delete_referrers: bool,
+    /// `Accept` headers sent when fetching manifests, replacing [MANIFEST_MEDIA_TYPES] if not empty
+    pub manifest_accept: Vec<String>,
+    /// Ramp up the number of requests in flight from one to `max_in_flight` over this long, rather than starting at full speed
+    pub warmup: Option<Duration>,
+    /// The most requests that can be in flight at once, which the warm-up ramps up to
+    pub max_in_flight: usize,
+    /// Manifests and blobs larger than this are rejected rather than read into memory
+    pub max_response_bytes: u64,
+    /// If not empty, only these platforms of manifest lists are considered, as `os/architecture` or `os/architecture/variant`
+    pub platforms: Vec<String>,
+    /// What happens to images whose creation date can't be parsed
+    pub on_parse_error: DateParseErrors,
+}
+
+/// How images are deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DeleteMode {
+    /// Delete the manifest by digest. This is supported by every registry implementing the
+    /// distribution spec, e.g. the Docker registry with deletion enabled, but also removes every
+    /// other tag pointing at the same manifest
+    Manifest,
+    /// Delete only the tag, by sending the tag name instead of the digest. This is optional in the
+    /// distribution spec; it is supported by e.g. Harbor and Zot, while the Docker registry rejects it
+    Tag,
+}
+
+/// What happens to images whose creation date can't be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DateParseErrors {
+    /// Leave the image alone, without counting it as a failure
+    Skip,
+    /// Treat the image as created just now, so it's kept and counts towards `--keep-last`
+    Keep,
+    /// Treat the image as older than any retention, so it's deleted
+    Delete,
+}
+
+/// Where the age of an image is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AgeSource {
+    /// The `created` timestamp in the image config, i.e. when the image was built
+    Created,
+    /// The `Last-Modified` header of the manifest, i.e. when the image was pushed.
+    /// Falls back to `created` for registries which don't return it
+    Pushed,
+}
+
+pub struct Registry {
+    /// Base URL of the registry, always ending in a `/` so paths can be joined onto it
+    base: Url,
+    client: Client,
+    credentials: Option<Credentials>,
+    ecr: Option<EcrLogin>,
+    options: RegistryOptions,
+    /// Bearer tokens, keyed by the scope they were requested for. Each scope has its own lock,
+    /// so fetching a token only makes requests for the same scope wait
+    tokens: std::sync::Mutex<HashMap<String, Arc<Mutex<Option<Token>>>>>,
+    rate_limiter: Option<DefaultDirectRateLimiter>,
+    warmup: Option<Warmup>,
+    /// Total time requests spent waiting on the rate limiter, in microseconds
+    rate_limited: AtomicU64,
+    /// Number of retries so far, counted against [RegistryOptions::retry_budget]
+    retries: AtomicU64,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.base.as_str())
+    }
+}
+
+impl Registry {
+    pub fn new(url: &str, client: Client, credentials: Option<Credentials>, options: RegistryOptions) -> Result<Self> {
+        let mut base = Url::parse(url)?;
+        // Without the trailing slash, joining would replace the last path segment
+        // rather than append to it, breaking registries served under a subpath
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+
+        let rate_limiter = match options.rate_limit {
+            Some(rps) => {
+                let period = Duration::try_from_secs_f64(1.0 / rps).ok()
+                    .and_then(Quota::with_period)
+                    .ok_or_else(|| DrcError::Config(format!("Invalid rate limit {rps}, it must be a positive number of requests per second")))?;
+                Some(RateLimiter::direct(period))
+            },
+            None => None,
+        };
+
+        let warmup = options.warmup.map(|duration| Warmup::new(duration, options.max_in_flight));
+
+        Ok(Self {
+            base,
+            client,
+            credentials,
+            ecr: options.ecr_region.clone().map(EcrLogin::new),
+            options,
+            tokens: std::sync::Mutex::new(HashMap::new()),
+            rate_limiter,
+            warmup,
+            rate_limited: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+        })
+    }
+
+    /// The credentials to authenticate with, if any
+    async fn credentials(&self) -> Result<Option<Credentials>> {
+        match &self.ecr {
+            Some(ecr) => Ok(Some(ecr.credentials().await.map_err(DrcError::Credentials)?)),
+            None => Ok(self.credentials.clone()),
+        }
+    }
+
+    /// Total time requests were delayed by the rate limit
+    pub fn rate_limited(&self) -> Duration {
+        Duration::from_micros(self.rate_limited.load(Ordering::Relaxed))
+    }
+
+    /// The HTTP client used for the registry, which can be reused for other requests
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Read the body of a response, failing once it grows larger than `max_response_bytes`
+    async fn read_body(&self, mut response: Response) -> Result<Vec<u8>> {
+        let limit = self.options.max_response_bytes;
+        let too_large = |response: &Response| DrcError::ResponseTooLarge { url: response.url().to_string(), limit };
+        if response.content_length().is_some_and(|x| x > limit) {
+            return Err(too_large(&response));
+        }
+
+        // The length isn't always known up front, so it's checked while reading too
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if (body.len() + chunk.len()) as u64 > limit {
+                return Err(too_large(&response));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// Like [Response::json], but limited to `max_response_bytes`
+    async fn read_json<T: DeserializeOwned>(&self, response: Response) -> Result<T> {
+        Ok(serde_json::from_slice(&self.read_body(response).await?)?)
+    }
+
+    /// Add the `Accept` headers for fetching a manifest to `request`
+    fn accept_manifests(&self, request: RequestBuilder) -> RequestBuilder {
+        if self.options.manifest_accept.is_empty() {
+            return request.header("Accept", MANIFEST_MEDIA_TYPES.join(", "));
+        }
+
+        // Sent as given, for registries which are picky about the exact header
+        self.options.manifest_accept.iter()
+            .fold(request, |request, accept| request.header("Accept", accept))
+    }
+
+    /// The URL of an API endpoint, e.g. `v2/_catalog`, relative to the registry's base URL
+    fn endpoint(&self, path: &str) -> Result<Url> {
+        let mut url = self.base.join(path.trim_start_matches('/'))?;
+        if url.query().is_none() {
+            url.set_query(self.base.query());
+        }
+        Ok(url)
+    }
+
+    /// Resolve a link returned by the registry, like the `Link` header used for pagination.
+    /// Registries behind a reverse proxy under a subpath don't know about that subpath,
+    /// so root-relative links to the API are resolved against the base URL instead
+    fn resolve_link(&self, response_url: &Url, link: &str) -> Result<Url> {
+        if link.starts_with("/v2/") {
+            self.endpoint(link)
+        } else {
+            Ok(response_url.join(link)?)
+        }
+    }
+
+    /// Send a request, performing the Docker token authentication flow if the registry asks for it.
+    /// `scope` is the token scope the request requires, e.g. `repository:foo:pull`.
+    /// If no token is known, credentials are sent as Basic auth, which is all registries without a token server need.
+    /// Requests failing with a transient error are retried with exponential backoff
+    async fn send(&self, request: RequestBuilder, scope: &str) -> Result<Response> {
+        let request = match &self.credentials().await? {
+            Some(credentials) => request.basic_auth(&credentials.username, Some(&credentials.password)),
+            None => request,
+        };
+        let request = request.build()?;
+
+        let mut attempt = 0;
+        loop {
+            // Requests without a body can always be cloned
+            let result = match request.try_clone() {
+                Some(request) => self.send_authenticated(request, scope).await,
+                None => return self.send_authenticated(request, scope).await,
+            };
+
+            if attempt >= self.options.max_retries || !is_retryable(&result) {
+                return result;
+            }
+            // A registry which is down would otherwise be retried for every request
+            if !self.take_retry() {
+                return result;
+            }
+
+            let delay = match &result {
+                Ok(response) => {
+                    warn!("Request to {} failed with status {}, retrying ({}/{})", request.url(), response.status(), attempt + 1, self.options.max_retries);
+                    retry_after(response).unwrap_or_else(|| self.backoff(attempt))
+                },
+                Err(e) => {
+                    warn!("Request to {} failed: {e}, retrying ({}/{})", request.url(), attempt + 1, self.options.max_retries);
+                    self.backoff(attempt)
+                },
+            };
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Count a retry against the retry budget, returning whether there was any left
+    fn take_retry(&self) -> bool {
+        let budget = match self.options.retry_budget {
+            Some(budget) => budget,
+            None => return true,
+        };
+
+        let used = self.retries.fetch_add(1, Ordering::Relaxed);
+        if used == budget {
+            warn!("Used up the retry budget of {budget} retries, failing requests are no longer retried");
+        }
+        used < budget
+    }
+
+    /// Exponential backoff with jitter, so concurrent requests that failed together don't retry together
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.options.retry_base_delay.saturating_mul(2u32.saturating_pow(attempt));
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+
+    /// Execute a request once the rate limit and warm-up allow it
+    async fn execute(&self, request: Request) -> Result<Response> {
+        let _permit = match &self.warmup {
+            Some(warmup) => warmup.acquire().await,
+            None => None,
+        };
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let start = Instant::now();
+            rate_limiter.until_ready().await;
+            self.rate_limited.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        }
+
+        self.execute_logged(request).await
+    }
+
+    /// Execute a request, logging the traffic if `verbose_http` is set
+    async fn execute_logged(&self, request: Request) -> Result<Response> {
+        if self.options.verbose_http {
+            log_request(&request);
+        }
+
+        let response = self.client.execute(request).await?;
+        if self.options.verbose_http {
+            log_response(&response);
+        }
+
+        Ok(response)
+    }
+
+    async fn send_authenticated(&self, mut request: Request, scope: &str) -> Result<Response> {
+        let retry = request.try_clone();
+
+        if let Some(token) = self.token_slot(scope).lock().await.as_ref().filter(|x| x.expires_at > Instant::now()) {
+            // This replaces the Basic auth header
+            request.headers_mut().insert(AUTHORIZATION, bearer_header(&token.value)?);
+        }
+
+        let response = self.execute(request).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = response.headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(BearerChallenge::parse);
+        let (challenge, mut retry) = match (challenge, retry) {
+            (Some(challenge), Some(retry)) => (challenge, retry),
+            _ => return Ok(response),
+        };
+
+        let token = self.fetch_token(scope, &challenge).await?;
+        retry.headers_mut().insert(AUTHORIZATION, bearer_header(&token)?);
+        self.execute(retry).await
+    }
+
+    /// Request a token from the challenge's realm, or reuse one another request fetched in the meantime
+    async fn fetch_token(&self, scope: &str, challenge: &BearerChallenge) -> Result<String> {
+        #[derive(Debug, Deserialize)]
+        struct Response {
+            token: Option<String>,
+            access_token: Option<String>,
+            expires_in: Option<u64>,
+        }
+
+        // The scope's lock is held while requesting the token, so concurrent requests
+        // for the same scope wait for this one rather than all hitting the auth server
+        let slot = self.token_slot(scope);
+        let mut token = slot.lock().await;
+        if let Some(token) = token.as_ref().filter(|x| x.expires_at > Instant::now()) {
+            return Ok(token.value.clone());
+        }
+
+        debug!("Requesting token for scope {scope} from {}", challenge.realm);
+        let mut query = Vec::new();
+        if let Some(service) = &challenge.service {
+            query.push(("service", service.as_str()));
+        }
+        // The challenge's scope is what this request needs, which isn't always what we need (e.g. ghcr.io asks for pull when deleting),
+        // so both are requested
+        if !scope.is_empty() {
+            query.push(("scope", scope));
+        }
+        if let Some(challenge_scope) = challenge.scope.as_deref().filter(|x| *x != scope && !x.is_empty()) {
+            query.push(("scope", challenge_scope));
+        }
+
+        let request = || self.client.get(&challenge.realm).query(&query);
+        let credentials = self.credentials().await?;
+        let response = match &credentials {
+            Some(credentials) => self.execute_logged(request().basic_auth(&credentials.username, Some(&credentials.password)).build()?).await?,
+            None => self.execute_logged(request().build()?).await?,
+        };
+
+        // Public images on e.g. ghcr.io can be pulled with an anonymous token,
+        // even when the credentials only have access to other packages
+        let response = match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN if credentials.is_some() && scope.ends_with(":pull") => {
+                debug!("Token server rejected the credentials for scope {scope}, requesting an anonymous token");
+                self.execute_logged(request().build()?).await?
+            },
+            _ => response,
+        };
+
+        let response: Response = response
+            .check_status()?
+            .json()
+            .await?;
+
+        let value = response.token
+            .or(response.access_token)
+            .ok_or_else(|| DrcError::Parse(format!("Token server at {} did not return a token", challenge.realm)))?;
+        let expires_in = response.expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOKEN_EXPIRY);
+
+        *token = Some(Token {
+            value: value.clone(),
+            expires_at: Instant::now() + expires_in.saturating_sub(TOKEN_EXPIRY_MARGIN),
+        });
+        Ok(value)
+    }
+
+    /// The token of `scope`, locked while it's being fetched
+    fn token_slot(&self, scope: &str) -> Arc<Mutex<Option<Token>>> {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.entry(scope.to_string()).or_default().clone()
+    }
+}
+
+/// Limits the requests in flight while warming up, starting at one and doubling
+/// at regular intervals until `max` is reached at the end of the warm-up
+struct Warmup {
+    semaphore: Semaphore,
+    start: Instant,
+    duration: Duration,
+    max: usize,
+    /// The number of permits added to the semaphore so far
+    permits: std::sync::Mutex<usize>,
+}
+
+impl Warmup {
+    fn new(duration: Duration, max: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(1),
+            start: Instant::now(),
+            duration,
+            max: max.max(1),
+            permits: std::sync::Mutex::new(1),
+        }
+    }
+
+    /// The number of requests allowed in flight right now
+    fn limit(&self) -> usize {
+        let elapsed = self.start.elapsed();
+        if elapsed >= self.duration {
+            return self.max;
+        }
+
+        // The number of doublings needed to get from 1 to max
+        let steps = usize::BITS - (self.max - 1).leading_zeros();
+        let step = (elapsed.as_secs_f64() / self.duration.as_secs_f64() * steps as f64) as u32;
+        1usize.checked_shl(step).unwrap_or(usize::MAX).min(self.max)
+    }
+
+    /// Wait until another request is allowed in flight. Returns `None` once warmed up, as there's nothing left to limit
+    async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        {
+            // Permits are added as requests come in, rather than by a background task
+            let mut permits = self.permits.lock().unwrap();
+            let limit = self.limit();
+            if limit > *permits {
+                self.semaphore.add_permits(limit - *permits);
+                *permits = limit;
+            }
+            if *permits >= self.max {
+                return None;
+            }
+        }
+
+        self.semaphore.acquire().await.ok()
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    // Not Implemented won't go away by retrying
+    (status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED) || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_retryable(result: &Result<Response>) -> bool {
+    match result {
+        Ok(response) => is_retryable_status(response.status()),
+        Err(e) => e.is_transient(),
+    }
+}
+
+fn log_request(request: &Request) {
+    let headers = request.headers().iter()
+        .map(|(name, value)| {
+            let value = if name == AUTHORIZATION { "<redacted>" } else { value.to_str().unwrap_or("<binary>") };
+            format!("{name}: {value}")
+        })
+        .collect::<Vec<_>>();
+    trace!("> {} {} [{}]", request.method(), request.url(), headers.join(", "));
+}
+
+fn log_response(response: &Response) {
+    // These are the headers relevant to authentication, pagination and retries
+    let headers = [WWW_AUTHENTICATE, LINK, RETRY_AFTER, CONTENT_TYPE].iter()
+        .filter_map(|name| Some(format!("{name}: {}", response.headers().get(name)?.to_str().ok()?)))
+        .collect::<Vec<_>>();
+    trace!("< {} {} [{}]", response.status(), response.url(), headers.join(", "));
+}
+
+/// The delay requested by a `Retry-After` header, if it is given in seconds
+fn retry_after(response: &Response) -> Option<Duration> {
+    response.headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+fn bearer_header(token: &str) -> Result<HeaderValue> {
+    let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+        .map_err(|_| DrcError::Parse("The token server returned a token which isn't a valid header".to_string()))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// A parsed `WWW-Authenticate: Bearer realm="...",service="...",scope="..."` header
+#[derive(Debug)]
+struct BearerChallenge {
+    realm: String,
+    service: Option<String>,
+    scope: Option<String>,
+}
+
+impl BearerChallenge {
+    fn parse(header: &str) -> Option<Self> {
+        let (scheme, params) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+
+        let mut realm = None;
+        let mut service = None;
+        let mut scope = None;
+
+        let mut rest = params.trim();
+        while !rest.is_empty() {
+            let (key, after_key) = rest.split_once('=')?;
+            let after_key = after_key.trim_start();
+
+            // Values may be quoted, in which case they can contain commas (e.g. `scope="repository:foo:pull,push"`)
+            let (value, after_value) = match after_key.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"')?;
+                    (&quoted[..end], &quoted[end + 1..])
+                },
+                None => match after_key.find(',') {
+                    Some(end) => (&after_key[..end], &after_key[end..]),
+                    None => (after_key, ""),
+                }
+            };
+
+            match key.trim().to_ascii_lowercase().as_str() {
+                "realm" => realm = Some(value.to_string()),
+                "service" => service = Some(value.to_string()),
+                "scope" => scope = Some(value.to_string()),
+                _ => {}
+            }
+
+            rest = after_value.trim_start().trim_start_matches(',').trim_start();
+        }
+
+        Some(Self {
+            realm: realm?,
+            service,
+            scope,
+        })
+    }
+}
+
+/// A page of a paginated listing
+trait Page {
+    /// The names listed on this page, in order. The next page starts after the last one
+    fn entries(&self) -> &[String];
+}
+
+/// Fetch every page of a paginated listing endpoint, following `Link: <...>; rel="next"` headers.
+/// Registries which don't send these links are asked for the page after the last entry using the
+/// `last` parameter, as long as the pages are full.
+/// The listing starts after `start` if given, and stops once at least `limit` wanted entries were listed.
+/// With a `prefix`, only entries starting with it are wanted, and as listings are sorted the listing stops after them.
+/// Returns the pages and whether the listing is complete
+async fn get_paginated<T: DeserializeOwned + Page>(registry: &Registry, path: &str, scope: &str, start: Option<&str>, prefix: Option<&str>, limit: Option<usize>) -> Result<(Vec<T>, bool)> {
+    let (sender, mut receiver) = mpsc::channel(1);
+    let collect = async {
+        let mut pages = Vec::new();
+        while let Some(page) = receiver.recv().await {
+            pages.push(page);
+        }
+        pages
+    };
+
+    let (complete, pages) = tokio::join!(send_paginated(registry, path, scope, start, prefix, limit, sender), collect);
+    Ok((pages, complete?))
+}
+
+/// Like [get_paginated], but sends each page to `pages` as soon as it's fetched, so it can be processed while the next one is.
+/// The next page is only requested once there's room in the channel. Stops when the receiver is dropped, in which case the listing isn't complete
+async fn send_paginated<T: DeserializeOwned + Page>(registry: &Registry, path: &str, scope: &str, start: Option<&str>, prefix: Option<&str>, limit: Option<usize>, pages: mpsc::Sender<T>) -> Result<bool> {
+    let wanted = |x: &str| start.is_none_or(|start| x > start) && prefix.is_none_or(|prefix| x.starts_with(prefix));
+    let past_prefix = |x: &str| prefix.is_some_and(|prefix| x > prefix && !x.starts_with(prefix));
+
+    let page_url = |last: Option<&str>| -> Result<Url> {
+        let mut url = registry.endpoint(path)?;
+        if let Some(page_size) = registry.options.page_size {
+            url.query_pairs_mut().append_pair("n", &page_size.to_string());
+        }
+        if let Some(last) = last {
+            url.query_pairs_mut().append_pair("last", last);
+        }
+        Ok(url)
+    };
+
+    let mut next = Some(page_url(start)?);
+    let mut cursor = None;
+    let mut listed = 0;
+    while let Some(url) = next.take() {
+        // Listing a large catalog can take much longer than other requests
+        let mut request = registry.client.get(url);
+        if let Some(timeout) = registry.options.catalog_timeout {
+            request = request.timeout(timeout);
+        }
+        let response = registry.send(request, scope)
+            .await?
+            .check_status()?;
+
+        // The next link is usually relative to the registry root
+        let link = response.headers()
+            .get_all(LINK)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .find_map(parse_next_link)
+            .map(|x| registry.resolve_link(response.url(), x))
+            .transpose()?;
+        let page: T = response.json().await?;
+
+        // Registries which ignore `last` return the first page again
+        if cursor.is_some() && page.entries().last() == cursor.as_ref() {
+            break;
+        }
+
+        cursor = None;
+        next = match (link, registry.options.page_size) {
+            (Some(link), _) => Some(link),
+            // A page which isn't full is the last one
+            (None, Some(page_size)) if page.entries().len() >= page_size as usize => match page.entries().last() {
+                Some(last) => {
+                    cursor = Some(last.clone());
+                    Some(page_url(Some(last))?)
+                },
+                None => None,
+            },
+            (None, _) => None,
+        };
+        // Registries which ignore `last` list the entries before `start` as well, which don't count towards the limit
+        listed += page.entries().iter().filter(|x| wanted(x)).count();
+        let done = page.entries().last().is_some_and(|x| past_prefix(x));
+        if pages.send(page).await.is_err() {
+            return Ok(false);
+        }
+
+        if done {
+            return Ok(true);
+        }
+
+        if limit.is_some_and(|x| listed >= x) {
+            return Ok(next.is_none());
+        }
+    }
+
+    Ok(true)
+}
+
+/// Get the target of the `rel="next"` link in a `Link` header, if any
+fn parse_next_link(header: &str) -> Option<&str> {
+    header.split(',').find_map(|link| {
+        let mut parts = link.split(';');
+        let target = parts.next()?.trim().strip_prefix('<')?.strip_suffix('>')?;
+        let is_next = parts.any(|param| match param.split_once('=') {
+            Some((key, value)) => key.trim() == "rel" && value.trim().trim_matches('"') == "next",
+            None => false,
+        });
+
+        is_next.then_some(target)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Repository(pub String);
 
+impl fmt::Display for Repository {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Request the API version check endpoint, which succeeds if the registry implements the distribution API
+/// and accepts our credentials
+#[instrument]
+pub async fn check_api(registry: &Registry) -> Result<()> {
+    registry.send(registry.client.get(registry.endpoint("v2/")?), "")
+        .await?
+        .check_status()?;
+    Ok(())
+}
+
+/// A slice of the catalog, see [list_repositories]
+pub struct Catalog {
+    pub repositories: Vec<Repository>,
+    /// The last repository listed, if the catalog continues after it
+    pub next: Option<String>,
+}
+
+/// List the repositories in the catalog after `start`, or all of them. With a `namespace` (ending in a `/`), only those in it are listed.
+/// With a `limit`, at most this many are listed
 #[instrument]
-pub async fn list_repositories(registry: &str) -> Result<Vec<Repository>> {
+pub async fn list_repositories(registry: &Registry, start: Option<&str>, namespace: Option<&str>, limit: Option<usize>) -> Result<Catalog> {
+    let (sender, mut receiver) = mpsc::channel(1);
+    let collect = async {
+        let mut repositories = Vec::new();
+        while let Some(repository) = receiver.recv().await {
+            repositories.push(repository);
+        }
+        repositories
+    };
+
+    let (next, repositories) = tokio::join!(send_repositories(registry, start, namespace, limit, sender), collect);
+    Ok(Catalog { repositories, next: next? })
+}
+
+/// Like [list_repositories], but sends the repositories to `repositories` as soon as their page of the catalog is fetched,
+/// so they can be processed while the rest of the catalog is listed. Returns the last repository listed, if the catalog continues after it
+pub async fn send_repositories(registry: &Registry, start: Option<&str>, namespace: Option<&str>, limit: Option<usize>, repositories: mpsc::Sender<Repository>) -> Result<Option<String>> {
     #[derive(Debug, Deserialize)]
     struct Response {
         repositories: Option<Vec<String>>
     }
 
-    let response: Response = CLIENT.get(format!("{registry}/v2/_catalog"))
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
+    impl Page for Response {
+        fn entries(&self) -> &[String] {
+            self.repositories.as_deref().unwrap_or_default()
+        }
+    }
 
-    let result = response.repositories
-        .unwrap_or(Vec::new())
-        .into_iter().map(|x| Repository(x)).collect::<Vec<_>>();
-    Ok(result)
+    // The catalog is sorted, so the repositories in the namespace come right after its name
+    let start = match (start, namespace) {
+        (Some(start), Some(namespace)) => Some(start.max(namespace)),
+        (start, namespace) => start.or(namespace),
+    };
+
+    let (sender, mut pages) = mpsc::channel::<Response>(1);
+    let send = async move {
+        let mut listed = 0;
+        let mut last = None;
+        while let Some(page) = pages.recv().await {
+            // The catalog is sorted, which also catches registries ignoring `last`
+            let page = page.repositories.unwrap_or_default().into_iter()
+                .filter(|x| start.is_none_or(|start| x.as_str() > start) && namespace.is_none_or(|namespace| x.starts_with(namespace)));
+            for repository in page {
+                if limit.is_some_and(|x| listed >= x) {
+                    return (last, true);
+                }
+                // Nothing wants the rest of the catalog
+                if repositories.send(Repository(repository.clone())).await.is_err() {
+                    return (last, true);
+                }
+                listed += 1;
+                last = Some(repository);
+            }
+        }
+        (last, false)
+    };
+
+    let (complete, (last, truncated)) = tokio::join!(send_paginated(registry, "v2/_catalog", "registry:catalog:*", start, namespace, limit, sender), send);
+    let complete = match complete {
+        // ghcr.io, Docker Hub and others don't implement the catalog
+        Err(DrcError::NotFound(_)) => return Err(DrcError::Config(format!("{registry:?} does not support listing repositories, use --only-repository"))),
+        complete => complete?,
+    };
+
+    match complete && !truncated {
+        true => Ok(None),
+        false => Ok(last),
+    }
 }
 
-#[derive(Debug)]
+fn pull_scope(repository: &Repository) -> String {
+    format!("repository:{}:pull", repository.0)
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Tag<'a>(pub String, pub &'a Repository);
 
+impl fmt::Display for Tag<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.1.0, self.0)
+    }
+}
+
 #[instrument]
-pub async fn list_tags<'a, 'b>(registry: &'b str, repository: &'a Repository) -> Result<Vec<Tag<'a>>> {
+pub async fn list_tags<'a, 'b>(registry: &'b Registry, repository: &'a Repository) -> Result<Vec<Tag<'a>>> {
     #[derive(Debug, Deserialize)]
     struct Response {
         tags: Option<Vec<String>>
     }
 
-    let response: Response = CLIENT.get(format!("{registry}/v2/{}/tags/list", repository.0))
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
+    impl Page for Response {
+        fn entries(&self) -> &[String] {
+            self.tags.as_deref().unwrap_or_default()
+        }
+    }
 
-    let result = response.tags
-        .unwrap_or(Vec::default())
-        .into_iter().map(|x| Tag(x, repository)).collect::<Vec<_>>();
+    let (pages, _): (Vec<Response>, _) = get_paginated(registry, &format!("v2/{}/tags/list", repository.0), &pull_scope(repository), None, None, None).await?;
+
+    let result = pages.into_iter()
+        .flat_map(|x| x.tags.unwrap_or(Vec::default()))
+        .map(|x| Tag(x, repository)).collect::<Vec<_>>();
     Ok(result)
 }
 
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const OCI_IMAGE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+/// Schema 1 manifests, only served by legacy registries or for images pushed by very old clients
+const DOCKER_MANIFEST_V1_SIGNED: &str = "application/vnd.docker.distribution.manifest.v1+prettyjws";
+const DOCKER_MANIFEST_V1: &str = "application/vnd.docker.distribution.manifest.v1+json";
+/// Every manifest media type we can handle. Image manifests and indexes are told apart by [Manifest::is_index].
+/// Schema 1 is listed last, so registries only fall back to it when they can't serve anything else
+const MANIFEST_MEDIA_TYPES: [&str; 6] = [DOCKER_MANIFEST, DOCKER_MANIFEST_LIST, OCI_IMAGE_MANIFEST, OCI_IMAGE_INDEX, DOCKER_MANIFEST_V1_SIGNED, DOCKER_MANIFEST_V1];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    media_type: Option<String>,
+    /// Only present for image manifests
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    /// Only present for manifest lists and image indexes
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    schema_version: Option<u32>,
+    /// Only present for schema 1 manifests, which have no config. The newest entry comes first
+    #[serde(default)]
+    history: Vec<V1History>,
+    /// The manifest this one refers to, e.g. the image a signature belongs to
+    subject: Option<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct V1History {
+    /// JSON encoded image config of this layer
+    v1_compatibility: String,
+}
+
+impl Manifest {
+    /// Schema 1 manifests don't carry a media type in the body, so the schema version is checked as well
+    fn is_v1(&self) -> bool {
+        matches!(self.media_type.as_deref(), Some(DOCKER_MANIFEST_V1_SIGNED | DOCKER_MANIFEST_V1)) || self.schema_version == Some(1)
+    }
+
+    /// The creation date of a schema 1 manifest, from the config of its top layer. It's parsed along
+    /// with the dates of other images, so it's handled the same way if it can't be
+    fn v1_created(&self) -> Result<serde_json::Value> {
+        #[derive(Debug, Deserialize)]
+        struct V1Compatibility {
+            created: serde_json::Value,
+        }
+
+        let history = self.history.first().ok_or_else(|| DrcError::Parse("Schema 1 manifest has no history".to_string()))?;
+        let compatibility: V1Compatibility = serde_json::from_str(&history.v1_compatibility)?;
+        Ok(compatibility.created)
+    }
+
+    fn is_index(&self) -> bool {
+        match self.media_type.as_deref() {
+            Some(DOCKER_MANIFEST_LIST | OCI_IMAGE_INDEX) => true,
+            // The media type is optional in OCI indexes
+            Some(_) => false,
+            None => self.config.is_none() && !self.manifests.is_empty(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    digest: String,
+    #[serde(default)]
+    size: u64,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl Platform {
+    /// Whether this is the platform `filter`, written as `os/architecture` or `os/architecture/variant`.
+    /// Without a variant in the filter, every variant matches
+    fn matches(&self, filter: &str) -> bool {
+        let mut parts = filter.split('/');
+        parts.next() == Some(self.os.as_str())
+            && parts.next() == Some(self.architecture.as_str())
+            && parts.next().is_none_or(|variant| self.variant.as_deref() == Some(variant))
+    }
+}
+
+/// Fetch a manifest by tag or digest, returning its digest, the manifest, and when it was pushed if the registry tells us
+async fn get_manifest(registry: &Registry, repository: &Repository, reference: &str) -> Result<(String, Manifest, Option<i64>)> {
+    let request = registry.accept_manifests(registry.client.get(registry.endpoint(&format!("v2/{}/manifests/{}", repository.0, reference))?));
+    let response = registry.send(request, &pull_scope(repository))
+        .await?
+        .check_status()?;
+
+    let header_digest = response.headers()
+        .get("Docker-Content-Digest")
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string());
+    let last_modified = response.headers()
+        .get(LAST_MODIFIED)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| OffsetDateTime::parse(x, &time::format_description::well_known::Rfc2822).ok())
+        .map(|x| x.unix_timestamp());
+    let body = registry.read_body(response).await?;
+    // Registries should return the digest, but if they don't it is the hash of the exact bytes returned
+    let digest = header_digest.unwrap_or_else(|| format!("sha256:{:x}", Sha256::digest(&body)));
+
+    Ok((digest, serde_json::from_slice(&body)?, last_modified))
+}
+
+/// The digest of the manifest `reference` points to, using a `HEAD` request which doesn't transfer the manifest.
+/// Returns `None` if the registry doesn't include the digest in the response
+#[instrument]
+pub async fn head_manifest_digest(registry: &Registry, repository: &Repository, reference: &str) -> Result<Option<String>> {
+    // The digest depends on the format the manifest is served in, so this accepts the same formats as a GET
+    let request = registry.accept_manifests(registry.client.head(registry.endpoint(&format!("v2/{}/manifests/{}", repository.0, reference))?));
+    let response = registry.send(request, &pull_scope(repository))
+        .await?
+        .check_status()?;
+
+    Ok(response.headers()
+        .get("Docker-Content-Digest")
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string()))
+}
+
+/// A manifest in a repository, as listed by registries which list every manifest rather than only the tags
 #[derive(Debug)]
-pub struct TagDigest<'a>(String, pub &'a Tag<'a>);
+pub struct ListedManifest<'a> {
+    pub digest: String,
+    pub repository: &'a Repository,
+    /// Empty for untagged manifests
+    pub tags: Vec<String>,
+    pub media_type: Option<String>,
+    /// Size of the image in bytes, or 0 if the registry doesn't say
+    pub size: u64,
+    /// Unix timestamp the age of the image is computed from, according to the registry's [AgeSource]
+    pub date: Option<i64>,
+}
 
+impl ListedManifest<'_> {
+    pub fn is_index(&self) -> bool {
+        matches!(self.media_type.as_deref(), Some(DOCKER_MANIFEST_LIST | OCI_IMAGE_INDEX))
+    }
+}
+
+impl fmt::Display for ListedManifest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.repository, self.digest)
+    }
+}
+
+/// Every manifest in `repository`, including untagged ones.
+/// Google's registries list these alongside the tags, for other registries this returns `None`
 #[instrument]
-pub async fn get_tag_digest<'a, 'b>(registry: &'b str, tag: &'a Tag<'a>) -> Result<TagDigest<'a>> {
+pub async fn list_manifests<'a>(registry: &Registry, repository: &'a Repository) -> Result<Option<Vec<ListedManifest<'a>>>> {
+    // The numbers are strings, and the times are in milliseconds. Unknown times are 0
     #[derive(Debug, Deserialize)]
-    struct Response {
-        config: Config
+    #[serde(rename_all = "camelCase")]
+    struct Entry {
+        #[serde(default)]
+        tag: Vec<String>,
+        media_type: Option<String>,
+        image_size_bytes: Option<String>,
+        time_created_ms: Option<String>,
+        time_uploaded_ms: Option<String>,
     }
 
     #[derive(Debug, Deserialize)]
-    struct Config {
-        digest: String,
+    struct Response {
+        manifest: Option<HashMap<String, Entry>>,
     }
 
-    let response: Response = CLIENT.get(format!("{registry}/v2/{}/manifests/{}", tag.1.0, tag.0))
-        .header("Accept", "application/vnd.docker.distribution.manifest.v2+json")
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
+    // The manifests aren't ordered, so they can only be paginated with links
+    impl Page for Response {
+        fn entries(&self) -> &[String] {
+            &[]
+        }
+    }
+
+    let (pages, _): (Vec<Response>, _) = get_paginated(registry, &format!("v2/{}/tags/list", repository.0), &pull_scope(repository), None, None, None).await?;
+    if pages.iter().all(|x| x.manifest.is_none()) {
+        return Ok(None);
+    }
+
+    let parse_time = |x: Option<String>| x.and_then(|x| x.parse::<i64>().ok()).filter(|x| *x > 0).map(|x| x / 1000);
+    let result = pages.into_iter()
+        .flat_map(|x| x.manifest.unwrap_or_default())
+        .map(|(digest, entry)| {
+            let created = parse_time(entry.time_created_ms);
+            let date = match registry.options.age_source {
+                AgeSource::Created => created,
+                AgeSource::Pushed => parse_time(entry.time_uploaded_ms).or(created),
+            };
+
+            ListedManifest {
+                digest,
+                repository,
+                tags: entry.tag,
+                media_type: entry.media_type,
+                size: entry.image_size_bytes.and_then(|x| x.parse().ok()).unwrap_or(0),
+                date,
+            }
+        })
+        .collect();
+    Ok(Some(result))
+}
+
+/// The manifests a manifest depends on
+#[derive(Debug)]
+pub struct ManifestReferences {
+    /// The platform manifests of an index
+    pub manifests: Vec<String>,
+    /// The manifest a referrer, like a signature, belongs to
+    pub subject: Option<String>,
+}
+
+#[instrument]
+pub async fn get_manifest_references(registry: &Registry, repository: &Repository, digest: &str) -> Result<ManifestReferences> {
+    let (_, manifest, _) = get_manifest(registry, repository, digest).await?;
+    Ok(ManifestReferences {
+        manifests: manifest.manifests.into_iter().map(|x| x.digest).collect(),
+        subject: manifest.subject.map(|x| x.digest),
+    })
+}
+
+#[derive(Debug)]
+pub struct TagDigest<'a> {
+    /// Digest of the manifest the tag points to. For multi-arch images this is the digest of the index
+    pub digest: String,
+    /// Digests of the image config blobs. Multi-arch images have one per platform
+    pub configs: Vec<String>,
+    pub tag: &'a Tag<'a>,
+    /// Total size of the configs and layers, in bytes
+    pub size: u64,
+    /// Unix timestamp of when the manifest was pushed, if the registry returns it
+    pub pushed: Option<i64>,
+    /// Unix timestamp of when the image was created, if it is part of the manifest.
+    /// Only the case for schema 1 manifests, otherwise it is read from the configs
+    pub created: Option<serde_json::Value>,
+    /// A manifest list none of whose platforms match the platform filter, which is therefore left alone
+    pub excluded: bool,
+}
+
+impl fmt::Display for TagDigest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.tag, self.digest)
+    }
+}
+
+#[instrument]
+pub async fn get_tag_digest<'a, 'b>(registry: &'b Registry, tag: &'a Tag<'a>) -> Result<TagDigest<'a>> {
+    let (digest, manifest, pushed) = get_manifest(registry, tag.1, &tag.0).await?;
+
+    // Schema 1 manifests have no config or layer sizes, so the size is unknown
+    if manifest.is_v1() {
+        return Ok(TagDigest {
+            digest,
+            configs: Vec::new(),
+            tag,
+            size: 0,
+            pushed,
+            created: Some(manifest.v1_created()?),
+            excluded: false,
+        });
+    }
+
+    let platforms = &registry.options.platforms;
+    let mut excluded = false;
+    let manifests = if manifest.is_index() {
+        let mut manifests = Vec::with_capacity(manifest.manifests.len());
+        for descriptor in &manifest.manifests {
+            // Buildkit stores attestations as manifests with an unknown platform, these are not images
+            if descriptor.platform.as_ref().is_some_and(|x| x.os == "unknown") {
+                continue;
+            }
+            // Descriptors without a platform can't be filtered, so they're always considered
+            if let (Some(platform), false) = (&descriptor.platform, platforms.is_empty()) {
+                if !platforms.iter().any(|x| platform.matches(x)) {
+                    continue;
+                }
+            }
+
+            let (_, platform_manifest, _) = get_manifest(registry, tag.1, &descriptor.digest).await?;
+            manifests.push(platform_manifest);
+        }
 
-    Ok(TagDigest(response.config.digest, tag))
+        excluded = manifests.is_empty() && !platforms.is_empty();
+        if excluded {
+            warn!("None of the platforms of {tag} match --platform {}, leaving it alone", platforms.join(", "));
+        }
+        manifests
+    } else {
+        vec![manifest]
+    };
+
+    let mut configs = Vec::with_capacity(manifests.len());
+    let mut size = 0;
+    for manifest in manifests {
+        let config = manifest.config.ok_or_else(|| DrcError::Parse(format!("Manifest for {}:{} has no config", tag.1.0, tag.0)))?;
+        size += config.size + manifest.layers.iter().map(|x| x.size).sum::<u64>();
+        configs.push(config.digest);
+    }
+
+    Ok(TagDigest {
+        digest,
+        configs,
+        tag,
+        size,
+        pushed,
+        created: None,
+        excluded,
+    })
+}
+
+/// Like [get_tag_digest], but only the digest is fetched, using a `HEAD` request rather than transferring the manifest.
+/// Without the manifest there are no configs and the size is unknown, so it is `0`.
+/// Falls back to [get_tag_digest] if the registry doesn't return the digest
+#[instrument]
+pub async fn head_tag_digest<'a, 'b>(registry: &'b Registry, tag: &'a Tag<'a>) -> Result<TagDigest<'a>> {
+    match head_manifest_digest(registry, tag.1, &tag.0).await? {
+        Some(digest) => Ok(TagDigest {
+            digest,
+            configs: Vec::new(),
+            tag,
+            size: 0,
+            pushed: None,
+            created: None,
+            excluded: false,
+        }),
+        None => get_tag_digest(registry, tag).await,
+    }
 }
 
 #[derive(Debug)]
 pub struct Blob<'a> {
     pub tag_digest: &'a TagDigest<'a>,
     pub date: i64,
+    /// Total size of the image in bytes, see [TagDigest::size]
+    pub size: u64,
+}
+
+impl fmt::Display for Blob<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.tag_digest.fmt(f)
+    }
+}
+
+#[instrument]
+pub async fn get_blob<'a, 'b>(registry: &'b Registry, digest: &'a TagDigest<'a>) -> Result<Blob<'a>> {
+    Ok(Blob {
+        tag_digest: digest,
+        date: get_image_date(registry, digest).await?,
+        size: digest.size,
+    })
 }
 
+/// Unix timestamp the age of an image is computed from, according to the registry's [AgeSource]
 #[instrument]
-pub async fn get_blob<'a, 'b>(registry: &'b str, digest: &'a TagDigest<'a>) -> Result<Blob<'a>> {
+pub async fn get_image_date(registry: &Registry, digest: &TagDigest<'_>) -> Result<i64> {
     #[derive(Debug, Deserialize)]
     struct Response {
-        created: String
+        created: serde_json::Value,
     }
 
-    let response: Response = CLIENT.get(format!("{registry}/v2/{}/blobs/{}", digest.1.1.0, digest.0))
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
+    if let (AgeSource::Pushed, Some(pushed)) = (registry.options.age_source, digest.pushed) {
+        return Ok(pushed);
+    }
 
-    let parsed_time = OffsetDateTime::parse(&response.created, &time::format_description::well_known::Iso8601::PARSING)?;
-    Ok(Blob {
-        tag_digest: digest,
-        date: parsed_time.unix_timestamp()
-    })
+    if let Some(created) = &digest.created {
+        return parse_image_date(registry, created, digest);
+    }
+
+    // For multi-arch images, the image is as new as its newest platform
+    let mut date = None;
+    for config in &digest.configs {
+        let response = registry.send(registry.client.get(registry.endpoint(&format!("v2/{}/blobs/{}", digest.tag.1.0, config))?), &pull_scope(digest.tag.1))
+            .await?
+            .check_status()?;
+        let response: Response = registry.read_json(response).await?;
+
+        date = date.max(Some(parse_image_date(registry, &response.created, digest)?));
+    }
+
+    date.ok_or_else(|| DrcError::Parse(format!("Image {}:{} has no platform images", digest.tag.1.0, digest.tag.0)))
+}
+
+/// Parse the `created` timestamp of an image, falling back to [RegistryOptions::on_parse_error] if it can't be
+fn parse_image_date(registry: &Registry, created: &serde_json::Value, digest: &TagDigest<'_>) -> Result<i64> {
+    match parse_created(created) {
+        Some(parsed_time) => Ok(parsed_time),
+        None => {
+            warn!("Can't parse the creation date {created} of {}:{}", digest.tag.1.0, digest.tag.0);
+            match registry.options.on_parse_error {
+                DateParseErrors::Skip => Err(DrcError::InvalidDate(digest.tag.to_string())),
+                DateParseErrors::Keep => Ok(OffsetDateTime::now_utc().unix_timestamp()),
+                DateParseErrors::Delete => Ok(0),
+            }
+        }
+    }
+}
+
+/// Unix timestamp of a `created` date, which should be ISO 8601 but is sometimes RFC 3339 or
+/// Unix time, in seconds or milliseconds, depending on what built the image
+fn parse_created(created: &serde_json::Value) -> Option<i64> {
+    use time::format_description::well_known::{Iso8601, Rfc3339};
+
+    // Seconds wouldn't reach this until the year 5138
+    let from_epoch = |x: i64| if x > 100_000_000_000 { x / 1000 } else { x };
+    match created {
+        serde_json::Value::String(created) => OffsetDateTime::parse(created, &Iso8601::PARSING).ok()
+            .or_else(|| OffsetDateTime::parse(created, &Rfc3339).ok())
+            .map(|x| x.unix_timestamp())
+            .or_else(|| created.trim().parse::<i64>().ok().map(from_epoch)),
+        serde_json::Value::Number(created) => created.as_i64()
+            .or_else(|| created.as_f64().map(|x| x as i64))
+            .map(from_epoch),
+        _ => None,
+    }
 }
 
+/// Delete the image `tag` points to with manifest `digest`, either by digest or by tag depending on the delete mode
 #[instrument]
-pub async fn delete_digest(registry: &str, digest: &TagDigest<'_>) -> Result<()> {
-    CLIENT.delete(format!("{registry}/v2/{}/manifests/{}", digest.1.1.0, digest.0))
-        .send()
-        .await?
-        .error_for_status()?;
+pub async fn delete_image(registry: &Registry, repository: &Repository, tag: &str, digest: &str) -> Result<()> {
+    match registry.options.delete_mode {
+        DeleteMode::Manifest => {
+            // Referrers are deleted first, so they aren't left behind if deleting the image fails
+            if registry.options.delete_referrers {
+                delete_referrers(registry, repository, digest).await?;
+            }
+            delete_manifest(registry, repository, digest).await
+        },
+        // The manifest stays, so its referrers do too
+        DeleteMode::Tag => delete_manifest(registry, repository, tag).await,
+    }
+}
+
+/// Check whether [delete_image] would be permitted, without deleting anything. Returns why not if it isn't.
+/// This is best effort: `OPTIONS` tells whether the registry allows `DELETE` on manifests at all,
+/// and sending it with the delete scope whether we're granted that scope. Registries which have deletion
+/// disabled in their storage, or token servers which silently grant fewer actions than asked for, aren't detected
+#[instrument]
+pub async fn check_deletable(registry: &Registry, repository: &Repository, tag: &str, digest: &str) -> Result<Option<String>> {
+    let reference = match registry.options.delete_mode {
+        DeleteMode::Manifest => digest,
+        DeleteMode::Tag => tag,
+    };
+    let scope = format!("repository:{}:delete", repository.0);
+    let url = registry.endpoint(&format!("v2/{}/manifests/{}", repository.0, reference))?;
+    let response = registry.send(registry.client.request(Method::OPTIONS, url.clone()), &scope).await?;
+
+    let status = response.status();
+    if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        return Ok(Some(format!("The credentials don't allow deleting from {repository} (status {status})")));
+    }
+
+    let allow = response.headers().get(ALLOW).and_then(|x| x.to_str().ok());
+    if let Some(allow) = allow {
+        if !allow.split(',').any(|x| x.trim().eq_ignore_ascii_case("DELETE")) {
+            return Ok(Some(format!("The registry doesn't allow deleting manifests, it only allows {allow}")));
+        }
+        return Ok(None);
+    }
+
+    // Without an Allow header, all we can check is that the delete scope is granted
+    match registry.send(registry.client.head(url), &scope).await?.check_status() {
+        Ok(_) | Err(DrcError::NotFound(_)) => Ok(None),
+        Err(DrcError::Unauthorized(_)) => Ok(Some(format!("The credentials don't allow deleting from {repository}"))),
+        Err(e) => Err(e),
+    }
+}
+
+/// Delete a manifest by digest, or a tag by name
+async fn delete_manifest(registry: &Registry, repository: &Repository, reference: &str) -> Result<()> {
+    let scope = format!("repository:{}:delete", repository.0);
+    let response = registry.send(registry.client.delete(registry.endpoint(&format!("v2/{}/manifests/{}", repository.0, reference))?), &scope)
+        .await?;
+
+    // Another run may have deleted the manifest since we listed it, which is what we wanted anyway
+    if response.status() == StatusCode::NOT_FOUND {
+        debug!("{repository}@{reference} was already deleted");
+        return Ok(());
+    }
+
+    response.check_status()?;
+    Ok(())
+}
+
+/// Delete everything referring to a manifest, including the referrers of referrers, like the signature of an SBOM
+async fn delete_referrers(registry: &Registry, repository: &Repository, digest: &str) -> Result<()> {
+    let mut pending = list_referrers(registry, repository, digest).await?;
+    while let Some(referrer) = pending.pop() {
+        pending.extend(list_referrers(registry, repository, &referrer).await?);
+        debug!("Deleting {repository}@{referrer}, which refers to {digest}");
+        delete_manifest(registry, repository, &referrer).await?;
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Digests of the artifacts referring to a manifest, like signatures and SBOMs, from the OCI referrers API.
+/// Registries which don't implement the API are treated as if there are no referrers
+#[instrument]
+pub async fn list_referrers(registry: &Registry, repository: &Repository, digest: &str) -> Result<Vec<String>> {
+    let response = registry.send(registry.client.get(registry.endpoint(&format!("v2/{}/referrers/{}", repository.0, digest))?), &pull_scope(repository))
+        .await?;
+
+    // Registries implementing the API return an empty index if there are no referrers, anything else means it's not implemented
+    if matches!(response.status(), StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED | StatusCode::BAD_REQUEST) {
+        debug!("Registry doesn't support the referrers API (status {})", response.status());
+        return Ok(Vec::new());
+    }
+
+    let index: Manifest = registry.read_json(response.check_status()?).await?;
+    Ok(index.manifests.into_iter().map(|x| x.digest).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::test_server::{options, StubResponse, StubServer};
+    use super::*;
+
+    #[test]
+    fn parse_next_link_relative() {
+        assert_eq!(parse_next_link(r#"</v2/_catalog?last=b&n=2>; rel="next""#), Some("/v2/_catalog?last=b&n=2"));
+    }
+
+    #[test]
+    fn parse_next_link_absolute() {
+        assert_eq!(parse_next_link("<https://registry.example.com/v2/_catalog?last=b>; rel=next"), Some("https://registry.example.com/v2/_catalog?last=b"));
+    }
+
+    #[test]
+    fn parse_next_link_among_others() {
+        assert_eq!(parse_next_link(r#"</v2/_catalog>; rel="first", </v2/_catalog?last=b>; rel="next""#), Some("/v2/_catalog?last=b"));
+        assert_eq!(parse_next_link(r#"</v2/_catalog>; rel="prev""#), None);
+    }
+
+    #[test]
+    fn endpoint_under_subpath() {
+        let registry = Registry::new("https://example.com/registry", Client::new(), None, options()).unwrap();
+        assert_eq!(registry.endpoint("v2/_catalog").unwrap().as_str(), "https://example.com/registry/v2/_catalog");
+        assert_eq!(registry.endpoint("/v2/foo/tags/list").unwrap().as_str(), "https://example.com/registry/v2/foo/tags/list");
+    }
+
+    #[test]
+    fn resolve_link_under_subpath() {
+        let registry = Registry::new("https://example.com/registry/", Client::new(), None, options()).unwrap();
+        let response_url = Url::parse("https://example.com/registry/v2/_catalog?n=2").unwrap();
+
+        // The registry behind the proxy doesn't know about the subpath
+        assert_eq!(registry.resolve_link(&response_url, "/v2/_catalog?last=b&n=2").unwrap().as_str(), "https://example.com/registry/v2/_catalog?last=b&n=2");
+        assert_eq!(registry.resolve_link(&response_url, "https://other.example.com/v2/_catalog?last=b").unwrap().as_str(), "https://other.example.com/v2/_catalog?last=b");
+        assert_eq!(registry.resolve_link(&response_url, "_catalog?last=b").unwrap().as_str(), "https://example.com/registry/v2/_catalog?last=b");
+    }
+
+    /// A catalog of `a`, `b` and `c` in pages of two, linking to the next page like the Docker registry does
+    fn paged_catalog(prefix: &'static str) -> impl Fn(&crate::test_server::StubRequest) -> StubResponse {
+        move |request| match (request.route().strip_prefix(prefix), request.query("last").first().map(String::as_str)) {
+            (Some("/v2/_catalog"), None) => StubResponse::json(json!({ "repositories": ["a", "b"] }))
+                .header("Link", r#"</v2/_catalog?last=b&n=2>; rel="next""#),
+            (Some("/v2/_catalog"), Some("b")) => StubResponse::json(json!({ "repositories": ["c"] })),
+            _ => StubResponse::status(404),
+        }
+    }
+
+    #[tokio::test]
+    async fn catalog_follows_link_header() {
+        let server = StubServer::start(paged_catalog("")).await;
+        let catalog = list_repositories(&server.registry(options()), None, None, None).await.unwrap();
+
+        let names = catalog.repositories.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(catalog.next, None);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn catalog_follows_link_header_under_subpath() {
+        let server = StubServer::start(paged_catalog("/registry")).await;
+        let registry = Registry::new(&format!("{}/registry", server.url), Client::new(), None, options()).unwrap();
+        let catalog = list_repositories(&registry, None, None, None).await.unwrap();
+
+        assert_eq!(catalog.repositories.len(), 3);
+        let paths = server.requests().into_iter().map(|x| x.path).collect::<Vec<_>>();
+        assert_eq!(paths, ["/registry/v2/_catalog", "/registry/v2/_catalog?last=b&n=2"]);
+    }
+
+    #[tokio::test]
+    async fn tags_follow_link_header() {
+        let server = StubServer::start(|request| match request.query("last").first().map(String::as_str) {
+            None => StubResponse::json(json!({ "name": "app", "tags": ["v1", "v2"] }))
+                .header("Link", r#"</v2/app/tags/list?last=v2&n=2>; rel="next""#),
+            Some("v2") => StubResponse::json(json!({ "name": "app", "tags": ["v3"] })),
+            Some(_) => StubResponse::status(404),
+        }).await;
+        let repository = Repository("app".to_string());
+        let tags = list_tags(&server.registry(options()), &repository).await.unwrap();
+
+        let names = tags.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["v1", "v2", "v3"]);
+    }
+
+    /// A multi-arch image for two platforms and a build attestation, like those pushed by buildx
+    fn manifest_list(request: &crate::test_server::StubRequest) -> StubResponse {
+        let platform_manifest = |config: &str, config_size: u64, layer_size: u64| StubResponse::json(json!({
+            "schemaVersion": 2,
+            "mediaType": DOCKER_MANIFEST,
+            "config": { "mediaType": "application/vnd.docker.container.image.v1+json", "digest": config, "size": config_size },
+            "layers": [
+                { "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "digest": "sha256:layer", "size": layer_size },
+            ],
+        }));
+
+        match (request.method.as_str(), request.route()) {
+            ("GET", "/v2/app/manifests/multi") => StubResponse::json(json!({
+                "schemaVersion": 2,
+                "mediaType": DOCKER_MANIFEST_LIST,
+                "manifests": [
+                    { "mediaType": DOCKER_MANIFEST, "digest": "sha256:amd64", "size": 428, "platform": { "architecture": "amd64", "os": "linux" } },
+                    { "mediaType": DOCKER_MANIFEST, "digest": "sha256:arm64", "size": 428, "platform": { "architecture": "arm64", "os": "linux", "variant": "v8" } },
+                    { "mediaType": DOCKER_MANIFEST, "digest": "sha256:attestation", "size": 566, "platform": { "architecture": "unknown", "os": "unknown" } },
+                ],
+            })).header("Docker-Content-Digest", "sha256:index"),
+            ("GET", "/v2/app/manifests/sha256:amd64") => platform_manifest("sha256:amd64-config", 1000, 20000),
+            ("GET", "/v2/app/manifests/sha256:arm64") => platform_manifest("sha256:arm64-config", 2000, 40000),
+            ("GET", "/v2/app/blobs/sha256:amd64-config") => StubResponse::json(json!({ "created": "2024-01-01T00:00:00Z" })),
+            ("GET", "/v2/app/blobs/sha256:arm64-config") => StubResponse::json(json!({ "created": "2024-01-02T00:00:00Z" })),
+            ("DELETE", "/v2/app/manifests/sha256:index") => StubResponse::status(202),
+            _ => StubResponse::status(404),
+        }
+    }
+
+    #[tokio::test]
+    async fn manifest_list_sums_platforms() {
+        let server = StubServer::start(manifest_list).await;
+        let registry = server.registry(options());
+        let repository = Repository("app".to_string());
+        let tag = Tag("multi".to_string(), &repository);
+
+        let digest = get_tag_digest(&registry, &tag).await.unwrap();
+        assert_eq!(digest.digest, "sha256:index");
+        assert_eq!(digest.configs, ["sha256:amd64-config", "sha256:arm64-config"]);
+        assert_eq!(digest.size, 1000 + 20000 + 2000 + 40000);
+        assert!(!digest.excluded);
+
+        // As new as its newest platform
+        let date = get_image_date(&registry, &digest).await.unwrap();
+        assert_eq!(date, 1704153600);
+    }
+
+    #[tokio::test]
+    async fn manifest_list_platform_filter() {
+        let server = StubServer::start(manifest_list).await;
+        let registry = server.registry(RegistryOptions {
+            platforms: vec!["linux/arm64".to_string()],
+            ..options()
+        });
+        let repository = Repository("app".to_string());
+        let tag = Tag("multi".to_string(), &repository);
+
+        let digest = get_tag_digest(&registry, &tag).await.unwrap();
+        assert_eq!(digest.configs, ["sha256:arm64-config"]);
+        assert_eq!(digest.size, 2000 + 40000);
+    }
+
+    #[tokio::test]
+    async fn manifest_list_deletes_index() {
+        let server = StubServer::start(manifest_list).await;
+        let registry = server.registry(options());
+        let repository = Repository("app".to_string());
+        let tag = Tag("multi".to_string(), &repository);
+
+        let digest = get_tag_digest(&registry, &tag).await.unwrap();
+        delete_image(&registry, &repository, &tag.0, &digest.digest).await.unwrap();
+
+        let deletes = server.requests().into_iter()
+            .filter(|x| x.method == "DELETE")
+            .map(|x| x.path)
+            .collect::<Vec<_>>();
+        assert_eq!(deletes, ["/v2/app/manifests/sha256:index"]);
+    }
+
+    /// A signed schema 1 manifest of `hello-world`, in the form legacy registries serve it. The newest history entry comes first
+    const SCHEMA_1_MANIFEST: &str = r#"{
+   "schemaVersion": 1,
+   "name": "library/hello-world",
+   "tag": "latest",
+   "architecture": "amd64",
+   "fsLayers": [
+      {
+         "blobSum": "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"
+      },
+      {
+         "blobSum": "sha256:719385e32844401d57ecfd3eacab360bf551a1491c05b85806ed8f1b08d792f6"
+      }
+   ],
+   "history": [
+      {
+         "v1Compatibility": "{\"architecture\":\"amd64\",\"config\":{\"Hostname\":\"\",\"Env\":[\"PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin\"],\"Cmd\":[\"/hello\"],\"Image\":\"sha256:62a15619037f3c4fb4e6ba9bd224cba3540e393a55dc52f6bebe212ca7b5e1a7\"},\"container\":\"347ca68872ee924c4f9394b195dcadaf591d387a45d624225251efc6cb7a348e\",\"created\":\"2023-05-02T16:49:27.18069177Z\",\"docker_version\":\"20.10.23\",\"id\":\"1e2e1ff3f5d8e3bc1ec2e4e2bd5e5b7d0e8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e\",\"os\":\"linux\",\"parent\":\"f05e7ab7e69d3b2a9c1d0e8f7a6b5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a8b\",\"throwaway\":true}"
+      },
+      {
+         "v1Compatibility": "{\"id\":\"f05e7ab7e69d3b2a9c1d0e8f7a6b5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a8b\",\"created\":\"2023-05-02T16:49:26.904391603Z\",\"container_config\":{\"Cmd\":[\"/bin/sh -c #(nop) COPY file:201f8f1849e89d53be9f6aa76937f5e209d745abfd15a8552fcf2ba45ab267f9 in / \"]}}"
+      }
+   ],
+   "signatures": [
+      {
+         "header": {
+            "jwk": {
+               "crv": "P-256",
+               "kid": "XQ6K:7UO3:NYID:CMDW:VBRH:H6MH:UCP7:7OHP:SHVO:ZAJI:2B5O:HEFQ",
+               "kty": "EC",
+               "x": "5DcHKVb5tT5R4LvHBrNQTAEyy6LSXiOXe8pEXbmxqzY",
+               "y": "FBiVGZuD3Zx0Y8V8nDFS-3Q3kPd2ZvGr6FcTum5G5OQ"
+            },
+            "alg": "ES256"
+         },
+         "signature": "ff0E4YcAxDCzMcbbSnh7fqLZpHHh3OQtkICrvHL2USEr4Bho2wHCwpswUcA3Jd6oR1wQlFabA8Du8OpK4dg3PQ",
+         "protected": "eyJmb3JtYXRMZW5ndGgiOjE3MTEsImZvcm1hdFRhaWwiOiJDbjAiLCJ0aW1lIjoiMjAyMy0wNS0wMlQxNjo1MDowMloifQ"
+      }
+   ]
+}"#;
+
+    #[test]
+    fn schema_1_created() {
+        let manifest: Manifest = serde_json::from_str(SCHEMA_1_MANIFEST).unwrap();
+        assert!(manifest.is_v1());
+        assert!(!manifest.is_index());
+
+        let created = manifest.v1_created().unwrap();
+        assert_eq!(created, "2023-05-02T16:49:27.18069177Z");
+        assert_eq!(parse_created(&created), Some(1683046167));
+    }
+
+    #[tokio::test]
+    async fn schema_1_image_date() {
+        let server = StubServer::start(|request| match request.route() {
+            "/v2/hello-world/manifests/latest" => StubResponse::status(200).body(DOCKER_MANIFEST_V1_SIGNED, SCHEMA_1_MANIFEST.to_string()),
+            _ => StubResponse::status(404),
+        }).await;
+        let registry = server.registry(options());
+        let repository = Repository("hello-world".to_string());
+        let tag = Tag("latest".to_string(), &repository);
+
+        // The date comes from the manifest, so no config blob is fetched
+        let digest = get_tag_digest(&registry, &tag).await.unwrap();
+        assert!(digest.configs.is_empty());
+        assert_eq!(get_image_date(&registry, &digest).await.unwrap(), 1683046167);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_spaces_requests() {
+        let server = StubServer::start(|_| StubResponse::json(json!({}))).await;
+        let registry = server.registry(RegistryOptions {
+            rate_limit: Some(20.0),
+            ..options()
+        });
+
+        // Sent at once, but only one is let through every 50ms
+        let start = Instant::now();
+        let results = futures::future::join_all((0..5).map(|_| check_api(&registry))).await;
+        let elapsed = start.elapsed();
+
+        assert!(results.iter().all(|x| x.is_ok()));
+        assert!(elapsed >= Duration::from_millis(190), "5 requests took {elapsed:?}");
+        assert!(registry.rate_limited() >= Duration::from_millis(190), "Waited {:?} on the rate limit", registry.rate_limited());
+    }
+
+    #[test]
+    fn invalid_rate_limit() {
+        let result = Registry::new("https://example.com", Client::new(), None, RegistryOptions { rate_limit: Some(0.0), ..options() });
+        assert!(matches!(result, Err(DrcError::Config(_))));
+    }
+
+    /// A listing of `entries` which honors the `n` and `last` parameters, without sending `Link` headers
+    fn cursor_listing(request: &crate::test_server::StubRequest, entries: &[&str]) -> Vec<String> {
+        let last = request.query("last").pop();
+        let n = request.query("n").pop().map_or(usize::MAX, |x| x.parse().unwrap());
+        entries.iter()
+            .filter(|x| last.as_deref().is_none_or(|last| **x > last))
+            .take(n)
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn tags_continue_after_last() {
+        let server = StubServer::start(|request| StubResponse::json(json!({ "tags": cursor_listing(request, &["a", "b", "c", "d", "e"]) }))).await;
+        let registry = server.registry(RegistryOptions { page_size: Some(2), ..options() });
+        let repository = Repository("app".to_string());
+        let tags = list_tags(&registry, &repository).await.unwrap();
+
+        assert_eq!(tags.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), ["a", "b", "c", "d", "e"]);
+        // The last page isn't full, so it's known to be the last one
+        let paths = server.requests().into_iter().map(|x| x.path).collect::<Vec<_>>();
+        assert_eq!(paths, ["/v2/app/tags/list?n=2", "/v2/app/tags/list?n=2&last=b", "/v2/app/tags/list?n=2&last=d"]);
+    }
+
+    #[tokio::test]
+    async fn tags_from_registry_ignoring_cursor() {
+        // Returns every tag, whatever is asked for
+        let server = StubServer::start(|_| StubResponse::json(json!({ "tags": ["a", "b", "c"] }))).await;
+        let registry = server.registry(RegistryOptions { page_size: Some(2), ..options() });
+        let repository = Repository("app".to_string());
+        let tags = list_tags(&registry, &repository).await.unwrap();
+
+        // The full page is repeated when asking for the next one, after which the listing stops
+        assert_eq!(tags.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn catalog_continues_after_cursor() {
+        let server = StubServer::start(|request| StubResponse::json(json!({ "repositories": cursor_listing(request, &["a", "b", "c", "d", "e"]) }))).await;
+        let registry = server.registry(RegistryOptions { page_size: Some(2), ..options() });
+
+        let catalog = list_repositories(&registry, Some("a"), None, Some(2)).await.unwrap();
+        assert_eq!(catalog.repositories.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), ["b", "c"]);
+        assert_eq!(catalog.next.as_deref(), Some("c"));
+
+        let catalog = list_repositories(&registry, Some("c"), None, Some(2)).await.unwrap();
+        assert_eq!(catalog.repositories.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), ["d", "e"]);
+        // A full page might not be the last one
+        assert_eq!(catalog.next.as_deref(), Some("e"));
+
+        let catalog = list_repositories(&registry, Some("e"), None, Some(2)).await.unwrap();
+        assert!(catalog.repositories.is_empty());
+        assert_eq!(catalog.next, None);
+    }
+
+    /// Responses as recorded from ghcr.io, where `user/img` is a public image the credentials have no access to
+    fn ghcr(request: &crate::test_server::StubRequest) -> StubResponse {
+        let denied = json!({ "errors": [{ "code": "DENIED", "message": "requested access to the resource is denied" }] });
+        let route = request.route();
+        if route == "/token" {
+            return match request.header("authorization") {
+                Some(x) if x.starts_with("Basic ") => StubResponse::json(denied).status_code(403),
+                _ => StubResponse::json(json!({ "token": "anon" })),
+            };
+        }
+        if request.header("authorization") != Some("Bearer anon") {
+            // ghcr.io challenges for pulling the repository, whatever the request is for
+            let challenge = format!(r#"Bearer realm="http://{}/token",service="ghcr.io",scope="repository:user/img:pull""#, request.header("host").unwrap());
+            return StubResponse::json(json!({ "errors": [{ "code": "UNAUTHORIZED", "message": "authentication required" }] }))
+                .status_code(401)
+                .header("WWW-Authenticate", &challenge);
+        }
+        match route {
+            "/v2/user/img/tags/list" => StubResponse::json(json!({ "name": "user/img", "tags": ["latest", "v1"] })),
+            _ => StubResponse::json(json!({ "errors": [{ "code": "NOT_FOUND", "message": "404 page not found" }] })).status_code(404),
+        }
+    }
+
+    #[tokio::test]
+    async fn ghcr_anonymous_token_for_public_image() {
+        let server = StubServer::start(ghcr).await;
+        let credentials = Credentials { username: "user".to_string(), password: "ghp_token".to_string() };
+        let registry = server.registry_with(credentials, options());
+        let repository = Repository("user/img".to_string());
+
+        let tags = list_tags(&registry, &repository).await.unwrap();
+        assert_eq!(tags.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(), ["latest", "v1"]);
+
+        // The PAT is tried first, after which an anonymous token is requested for the same scope
+        let tokens = server.requests().into_iter().filter(|x| x.route() == "/token").collect::<Vec<_>>();
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens[0].header("authorization").unwrap().starts_with("Basic "));
+        assert_eq!(tokens[1].header("authorization"), None);
+        for token in &tokens {
+            assert_eq!(token.query("service"), ["ghcr.io"]);
+            assert_eq!(token.query("scope"), ["repository:user/img:pull"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn ghcr_delete_requests_both_scopes() {
+        let server = StubServer::start(ghcr).await;
+        let registry = server.registry(options());
+        let repository = Repository("user/img".to_string());
+
+        // An anonymous token doesn't allow deleting, but what matters here is what's asked for
+        let _ = delete_manifest(&registry, &repository, "sha256:abc").await;
+        let token = server.requests().into_iter().find(|x| x.route() == "/token").unwrap();
+        assert_eq!(token.query("scope"), ["repository:user/img:delete", "repository:user/img:pull"]);
+    }
+
+    #[tokio::test]
+    async fn ghcr_has_no_catalog() {
+        let server = StubServer::start(ghcr).await;
+        let registry = server.registry(options());
+
+        let result = list_repositories(&registry, None, None, None).await;
+        assert!(matches!(result, Err(DrcError::Config(_))));
+        // The catalog scope is asked for, next to whatever the challenge asked for
+        let token = server.requests().into_iter().find(|x| x.route() == "/token").unwrap();
+        assert_eq!(token.query("scope"), ["registry:catalog:*", "repository:user/img:pull"]);
+    }
+}
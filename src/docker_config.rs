@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use reqwest::Url;
+use serde::Deserialize;
+use tracing::{debug, warn};
+use crate::docker::Credentials;
+
+/// The parts of the Docker CLI's `config.json` concerning credentials
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    /// Credential helper used for every registry without an entry in `cred_helpers`
+    creds_store: Option<String>,
+    /// Credential helpers per registry host
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthEntry {
+    /// Base64 encoded `username:password`
+    auth: Option<String>,
+}
+
+/// Output of `docker-credential-<helper> get`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Look up the credentials the Docker CLI uses for `registry`, from `$DOCKER_CONFIG/config.json`
+/// or `~/.docker/config.json`. Returns `None` if the config file doesn't exist or has no credentials for the registry
+pub fn find_credentials(registry: &str) -> Result<Option<Credentials>> {
+    let path = match config_path() {
+        Some(path) if path.exists() => path,
+        _ => {
+            warn!("No Docker config file found, continuing without credentials");
+            return Ok(None);
+        }
+    };
+
+    let contents = std::fs::read_to_string(&path).with_context(|| format!("Failed to read Docker config {}", path.display()))?;
+    let config: DockerConfig = serde_json::from_str(&contents).with_context(|| format!("Failed to parse Docker config {}", path.display()))?;
+
+    let host = registry_host(registry)?;
+    let helper = config.cred_helpers.iter()
+        .find(|(key, _)| normalize_host(key) == host)
+        .map(|(_, helper)| helper)
+        .or(config.creds_store.as_ref());
+    if let Some(helper) = helper {
+        if let Some(credentials) = run_helper(helper, &host)? {
+            return Ok(Some(credentials));
+        }
+    }
+
+    let auth = config.auths.iter()
+        .find(|(key, _)| normalize_host(key) == host)
+        .and_then(|(_, entry)| entry.auth.as_deref());
+    match auth {
+        Some(auth) => decode_auth(auth).map(Some),
+        None => {
+            warn!("No credentials for {host} in Docker config {}, continuing without credentials", path.display());
+            Ok(None)
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".docker"),
+    };
+    Some(dir.join("config.json"))
+}
+
+/// The host (and port, if any) of the registry, as the Docker CLI keys it
+fn registry_host(registry: &str) -> Result<String> {
+    let url = Url::parse(registry)?;
+    let host = url.host_str().ok_or_else(|| anyhow::anyhow!("Registry URL {registry} has no host"))?;
+    let host = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    Ok(normalize_host(&host))
+}
+
+/// Keys in the config may be bare hosts or full URLs, e.g. `https://index.docker.io/v1/` for Docker Hub
+fn normalize_host(key: &str) -> String {
+    let host = key.trim_start_matches("https://").trim_start_matches("http://");
+    let host = host.split('/').next().unwrap_or(host);
+    match host {
+        // Docker Hub is served from a different host than the one its credentials are stored under
+        "docker.io" | "registry-1.docker.io" => "index.docker.io".to_string(),
+        host => host.to_string(),
+    }
+}
+
+fn decode_auth(auth: &str) -> Result<Credentials> {
+    let decoded = base64::engine::general_purpose::STANDARD.decode(auth).context("Invalid auth in Docker config")?;
+    let decoded = String::from_utf8(decoded).context("Invalid auth in Docker config")?;
+    let (username, password) = decoded.split_once(':').ok_or_else(|| anyhow::anyhow!("Invalid auth in Docker config, expected username:password"))?;
+
+    Ok(Credentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// Get credentials from a credential helper, which reads the server from stdin and writes JSON to stdout.
+/// Returns `None` if the helper has no credentials for the server
+fn run_helper(helper: &str, host: &str) -> Result<Option<Credentials>> {
+    let program = format!("docker-credential-{helper}");
+    debug!("Getting credentials for {host} from {program}");
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run credential helper {program}"))?;
+
+    child.stdin.take().expect("stdin is piped").write_all(host.as_bytes())?;
+    let output = child.wait_with_output()?;
+    // Helpers report errors on stdout
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() && stdout.contains("credentials not found") {
+        debug!("Credential helper {program} has no credentials for {host}");
+        return Ok(None);
+    }
+    if !output.status.success() {
+        bail!("Credential helper {program} exited with {}: {}", output.status, stdout.trim());
+    }
+
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout).with_context(|| format!("Invalid output from credential helper {program}"))?;
+    if credentials.username == "<token>" {
+        bail!("Credential helper {program} returned an identity token, which is not supported");
+    }
+
+    Ok(Some(Credentials {
+        username: credentials.username,
+        password: credentials.secret,
+    }))
+}
@@ -0,0 +1,50 @@
+use anyhow::{bail, Result};
+use time::{Date, Duration, OffsetDateTime};
+use time::format_description::well_known::Iso8601;
+
+/// Parse a duration like `90d`, `12h`, `30m`, `45s` or `2w`.
+/// A plain number is interpreted as seconds
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    if input.starts_with('-') {
+        bail!("Invalid duration '{input}': durations can't be negative");
+    }
+
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (amount, unit) = input.split_at(split);
+
+    if amount.is_empty() {
+        bail!("Invalid duration '{input}': expected a number optionally followed by a unit, e.g. '90d'");
+    }
+    let amount: i64 = amount.parse()?;
+
+    let seconds_per_unit = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        // 'M' could mean either minutes or months
+        "M" => bail!("Invalid duration '{input}': 'M' is ambiguous, use 'm' for minutes or 'd' for days"),
+        _ => bail!("Invalid duration '{input}': unknown unit '{unit}', expected one of s, m, h, d or w"),
+    };
+
+    match amount.checked_mul(seconds_per_unit) {
+        Some(seconds) => Ok(Duration::seconds(seconds)),
+        None => bail!("Invalid duration '{input}': too large"),
+    }
+}
+
+/// Parse a point in time, either as an ISO 8601 date and time like `2024-01-31T12:00:00Z`,
+/// or as a date like `2024-01-31`, which means midnight UTC
+pub fn parse_date(input: &str) -> Result<OffsetDateTime> {
+    let input = input.trim();
+    if let Ok(datetime) = OffsetDateTime::parse(input, &Iso8601::PARSING) {
+        return Ok(datetime);
+    }
+
+    match Date::parse(input, &Iso8601::PARSING) {
+        Ok(date) => Ok(date.midnight().assume_utc()),
+        Err(_) => bail!("Invalid date '{input}': expected a date like '2024-01-31' or a date and time like '2024-01-31T12:00:00Z'"),
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use tokio::sync::Mutex;
+use tracing::debug;
+use crate::docker::Credentials;
+
+/// ECR authorization tokens are valid for 12 hours. They are refreshed an hour early,
+/// so a token never expires in the middle of a batch of requests
+const TOKEN_VALIDITY: Duration = Duration::from_secs(11 * 60 * 60);
+
+/// Credentials for AWS ECR, obtained through the AWS CLI
+#[derive(Debug)]
+pub struct EcrLogin {
+    region: String,
+    credentials: Mutex<Option<(Credentials, Instant)>>,
+}
+
+impl EcrLogin {
+    pub fn new(region: String) -> Self {
+        Self {
+            region,
+            credentials: Mutex::new(None),
+        }
+    }
+
+    /// The current credentials, logging in again if they are about to expire
+    pub async fn credentials(&self) -> Result<Credentials> {
+        let mut cached = self.credentials.lock().await;
+        if let Some((credentials, expires_at)) = &*cached {
+            if *expires_at > Instant::now() {
+                return Ok(credentials.clone());
+            }
+        }
+
+        let credentials = self.login().await?;
+        *cached = Some((credentials.clone(), Instant::now() + TOKEN_VALIDITY));
+        Ok(credentials)
+    }
+
+    async fn login(&self) -> Result<Credentials> {
+        debug!("Requesting ECR authorization token for region {}", self.region);
+        // The AWS CLI takes care of finding the AWS credentials, from the environment, profiles or instance metadata
+        let output = tokio::process::Command::new("aws")
+            .args(["ecr", "get-login-password", "--region", &self.region])
+            .output()
+            .await
+            .context("Failed to run 'aws ecr get-login-password', is the AWS CLI installed?")?;
+
+        if !output.status.success() {
+            bail!("'aws ecr get-login-password' exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        Ok(Credentials {
+            username: "AWS".to_string(),
+            password: String::from_utf8(output.stdout)?.trim().to_string(),
+        })
+    }
+}
@@ -0,0 +1,98 @@
+use reqwest::{Response, StatusCode};
+use thiserror::Error;
+
+pub type Result<T, E = DrcError> = std::result::Result<T, E>;
+
+/// Errors talking to a registry, so callers can tell e.g. a missing tag apart from invalid credentials
+#[derive(Debug, Error)]
+pub enum DrcError {
+    /// The registry rejected our credentials, or requires credentials we don't have
+    #[error("Unauthorized to access {0}, check the credentials")]
+    Unauthorized(String),
+    #[error("{0} was not found")]
+    NotFound(String),
+    /// The registry kept asking us to slow down, even after retrying
+    #[error("Rate limited by the registry when requesting {0}")]
+    RateLimited(String),
+    /// Any other unsuccessful status
+    #[error("Request to {url} failed with status {status}")]
+    Status { url: String, status: StatusCode },
+    /// The response is larger than we're willing to read into memory
+    #[error("Response from {url} is larger than {limit} bytes, see --max-response-bytes")]
+    ResponseTooLarge { url: String, limit: u64 },
+    /// The registry returned something we don't understand
+    #[error("Invalid response from the registry: {0}")]
+    Parse(String),
+    /// The creation date of an image couldn't be parsed, and [crate::docker::DateParseErrors::Skip] says to leave it alone
+    #[error("Image {0} has a creation date which can't be parsed")]
+    InvalidDate(String),
+    /// The request didn't make it to the registry, or the response didn't make it back
+    #[error(transparent)]
+    Transport(reqwest::Error),
+    /// Obtaining credentials, e.g. from ECR, failed
+    #[error("Failed to obtain credentials: {0:#}")]
+    Credentials(anyhow::Error),
+    /// Invalid options, like a malformed registry URL
+    #[error("{0}")]
+    Config(String),
+}
+
+impl DrcError {
+    /// Whether the error is likely to go away by itself, so the request is worth retrying
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Transport(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for DrcError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_decode() {
+            Self::Parse(error.to_string())
+        } else {
+            Self::Transport(error)
+        }
+    }
+}
+
+impl From<serde_json::Error> for DrcError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Parse(error.to_string())
+    }
+}
+
+impl From<time::error::Parse> for DrcError {
+    fn from(error: time::error::Parse) -> Self {
+        Self::Parse(error.to_string())
+    }
+}
+
+impl From<url::ParseError> for DrcError {
+    fn from(error: url::ParseError) -> Self {
+        Self::Config(format!("Invalid URL: {error}"))
+    }
+}
+
+/// Replacement for [Response::error_for_status], mapping the status to a [DrcError]
+pub(crate) trait CheckStatus: Sized {
+    fn check_status(self) -> Result<Self>;
+}
+
+impl CheckStatus for Response {
+    fn check_status(self) -> Result<Self> {
+        let status = self.status();
+        if status.is_success() {
+            return Ok(self);
+        }
+
+        let url = self.url().to_string();
+        Err(match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => DrcError::Unauthorized(url),
+            StatusCode::NOT_FOUND => DrcError::NotFound(url),
+            StatusCode::TOO_MANY_REQUESTS => DrcError::RateLimited(url),
+            status => DrcError::Status { url, status },
+        })
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use clap::ValueEnum;
+use glob::Pattern;
+use semver::Version;
+use serde::Serialize;
+
+/// How version tags are recognized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VersionDetection {
+    /// Any tag starting with 'v'
+    Prefix,
+    /// Any tag that is a valid semantic version, optionally prefixed with 'v'
+    Semver,
+}
+
+impl VersionDetection {
+    pub fn is_version(&self, tag: &str) -> bool {
+        match self {
+            Self::Prefix => tag.starts_with('v'),
+            Self::Semver => Version::parse(tag.strip_prefix('v').unwrap_or(tag)).is_ok(),
+        }
+    }
+}
+
+/// How version tags are grouped into version lines, of which only the newest version is always retained
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionLine {
+    /// e.g. `1.2.9` is the newest of `1.x.y`
+    Major,
+    /// e.g. `1.2.9` is the newest of `1.2.x`
+    Minor,
+}
+
+impl VersionLine {
+    /// The newest version of each version line among `tags`. Tags which aren't semantic versions, optionally prefixed with 'v', are ignored
+    pub fn newest<'a>(&self, tags: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+        let mut newest: HashMap<(u64, Option<u64>), (Version, &str)> = HashMap::new();
+        for tag in tags {
+            let Ok(version) = Version::parse(tag.strip_prefix('v').unwrap_or(tag)) else {
+                continue;
+            };
+
+            let line = match self {
+                Self::Major => (version.major, None),
+                Self::Minor => (version.major, Some(version.minor)),
+            };
+            match newest.get(&line) {
+                Some((current, _)) if *current >= version => {},
+                _ => { newest.insert(line, (version, tag)); },
+            }
+        }
+
+        newest.into_values().map(|(_, tag)| tag).collect()
+    }
+}
+
+/// Rules deciding which tags are 'kept', i.e. not counted as free tags
+#[derive(Debug)]
+pub struct KeepRules {
+    /// Whether the tag named `latest` is kept
+    pub latest: bool,
+    /// How version tags, which are kept, are recognized, or `None` if they aren't kept
+    pub versions: Option<VersionDetection>,
+    /// Tags matching any of these patterns are kept
+    pub patterns: Vec<Pattern>,
+}
+
+impl KeepRules {
+    pub fn is_kept(&self, tag: &str) -> bool {
+        (self.latest && tag.eq("latest"))
+            || self.versions.is_some_and(|x| x.is_version(tag))
+            || self.patterns.iter().any(|x| x.matches(tag))
+    }
+}
+
+/// Restricts which repositories are touched at all.
+/// Note that `*` also matches `/`, so `team-a/*` matches nested repositories like `team-a/app/db`
+#[derive(Debug)]
+pub struct RepositoryFilter {
+    /// If not empty, only repositories matching any of these are included
+    pub include: Vec<Pattern>,
+    /// Repositories matching any of these are excluded, even if they're included
+    pub exclude: Vec<Pattern>,
+    /// If set, only repositories under this path are included. Ends in a `/`
+    pub namespace: Option<String>,
+}
+
+impl RepositoryFilter {
+    pub fn matches(&self, repository: &str) -> bool {
+        if self.namespace.as_ref().is_some_and(|x| !repository.starts_with(x.as_str())) {
+            return false;
+        }
+        let included = self.include.is_empty() || self.include.iter().any(|x| x.matches(repository));
+        included && !self.exclude.iter().any(|x| x.matches(repository))
+    }
+}
+
+/// What to do with the tags cosign stores signatures, attestations and SBOMs under
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CosignHandling {
+    /// Never delete them
+    Ignore,
+    /// Delete them together with the image they belong to
+    Link,
+}
+
+/// The digest of the image a cosign artifact tag belongs to.
+/// Cosign tags its artifacts after the image's digest, e.g. `sha256-<hex>.sig` for `sha256:<hex>`
+pub fn cosign_subject(tag: &str) -> Option<String> {
+    let (name, suffix) = tag.rsplit_once('.')?;
+    let hex = name.strip_prefix("sha256-")?;
+    let is_artifact = matches!(suffix, "sig" | "att" | "sbom")
+        && hex.len() == 64
+        && hex.bytes().all(|x| x.is_ascii_hexdigit());
+
+    is_artifact.then(|| format!("sha256:{hex}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> RepositoryFilter {
+        RepositoryFilter {
+            include: include.iter().map(|x| Pattern::new(x).unwrap()).collect(),
+            exclude: exclude.iter().map(|x| Pattern::new(x).unwrap()).collect(),
+            namespace: None,
+        }
+    }
+
+    #[test]
+    fn nested_repositories() {
+        let filter = filter(&["team-a/*"], &[]);
+        assert!(filter.matches("team-a/app"));
+        assert!(filter.matches("team-a/app/db"));
+        assert!(!filter.matches("team-a"));
+        assert!(!filter.matches("team-b/app"));
+        assert!(!filter.matches("other/team-a/app"));
+    }
+
+    #[test]
+    fn nested_patterns() {
+        let filter = filter(&["*/ci-*", "team-?/app/db"], &[]);
+        assert!(filter.matches("team-a/ci-cache"));
+        assert!(filter.matches("team-a/sub/ci-cache"));
+        assert!(filter.matches("team-b/app/db"));
+        assert!(!filter.matches("ci-cache"));
+        assert!(!filter.matches("team-ab/app/db"));
+    }
+
+    #[test]
+    fn exclude_beats_include() {
+        let filter = filter(&["team-a/*"], &["team-a/*/cache", "team-a/legacy"]);
+        assert!(filter.matches("team-a/app"));
+        assert!(!filter.matches("team-a/app/cache"));
+        assert!(!filter.matches("team-a/legacy"));
+    }
+
+    #[test]
+    fn everything_included_by_default() {
+        assert!(filter(&[], &[]).matches("any/repository"));
+        assert!(!filter(&[], &["any/*"]).matches("any/repository"));
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::Stdio;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use crate::report::ImageReport;
+
+/// A manifest about to be deleted, as given to `--filter-command` on stdin.
+/// Tags sharing the manifest are asked about once, as deleting the manifest removes all of them
+#[derive(Debug, Serialize)]
+pub struct Candidate<'a> {
+    repository: &'a str,
+    digest: &'a str,
+    /// The tags of the manifest which are about to be deleted, empty for untagged manifests
+    tags: Vec<&'a str>,
+    age_seconds: i64,
+    size_bytes: u64,
+}
+
+impl fmt::Display for Candidate<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tags.as_slice() {
+            [] => write!(f, "{}@{}", self.repository, self.digest),
+            tags => write!(f, "{}:{}", self.repository, tags.join(",")),
+        }
+    }
+}
+
+impl<'a> Candidate<'a> {
+    /// Group the images by the manifest they'd delete
+    pub fn group(images: &'a [ImageReport]) -> Vec<Self> {
+        let mut candidates: BTreeMap<(&str, &str), Self> = BTreeMap::new();
+        for image in images {
+            let candidate = candidates.entry((&image.repository, &image.digest)).or_insert_with(|| Self {
+                repository: &image.repository,
+                digest: &image.digest,
+                tags: Vec::new(),
+                age_seconds: image.age_seconds,
+                size_bytes: image.size_bytes,
+            });
+            candidate.tags.extend(image.tag.as_deref());
+        }
+        candidates.into_values().collect()
+    }
+
+    pub fn key(&self) -> (String, String) {
+        (self.repository.to_string(), self.digest.to_string())
+    }
+
+    /// Ask `command` whether to delete the manifest. The command is run with `sh -c`, and gets the candidate as JSON on stdin,
+    /// as well as in the `DRC_REPOSITORY`, `DRC_DIGEST`, `DRC_TAGS` (comma separated) and `DRC_AGE_SECONDS` environment variables.
+    /// Exiting with 0 deletes the manifest and 1 keeps it. Any other exit code is a failure, which keeps it as well
+    pub async fn ask(&self, command: &str) -> Result<bool> {
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("DRC_REPOSITORY", self.repository)
+            .env("DRC_DIGEST", self.digest)
+            .env("DRC_TAGS", self.tags.join(","))
+            .env("DRC_AGE_SECONDS", self.age_seconds.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run filter command '{command}'"))?;
+
+        // Commands which don't read stdin close it early, which is fine
+        let input = serde_json::to_vec(self)?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(&input).await;
+        }
+
+        let output = child.wait_with_output().await
+            .with_context(|| format!("Failed to run filter command '{command}'"))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match output.status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => bail!("Filter command exited with {}: {}", output.status, stderr.trim()),
+        }
+    }
+}
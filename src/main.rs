@@ -1,171 +1,308 @@
-#![allow(warnings)]
-
-use std::collections::HashMap;
-use crate::args::Args;
-use crate::docker::{delete_digest, get_blob, get_tag_digest, list_repositories, list_tags, Repository, Tag, TagDigest};
-use anyhow::Result;
-use futures::future::join_all;
-use std::future::Future;
-use std::pin::Pin;
-use tracing::{debug, info, Level, warn};
-use tracing_subscriber::fmt::writer::MakeWriterExt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+use crate::args::{Args, Command};
+use anyhow::{bail, Context, Result};
+use docker_registry_cleaner::{fmt_bytes, Config, Drc};
+use docker_registry_cleaner::report::{DeletionReport, HealthCheck, Inventory, LogFormat, LogRotation, OutputFormat};
+use serde::Serialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info, info_span, Instrument, Level, Span, warn};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
 
 mod args;
-mod docker;
+
+// Exit codes. Invalid arguments exit with 2, like clap does
+/// The run completed, but some operations failed
+const EXIT_FAILED_OPERATIONS: i32 = 1;
+/// The run was aborted due to an error
+const EXIT_ERROR: i32 = 3;
+/// The run completed, but the report couldn't be written
+const EXIT_REPORT_FAILED: i32 = 4;
+/// The run was interrupted by SIGINT or SIGTERM, like shells report it for SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
 
 #[tokio::main]
 async fn main() {
     let args = Args::new();
-    configure_tracing(args.debug, args.trace);
+    if let Err(e) = configure_tracing(&args) {
+        eprintln!("{e:#}");
+        std::process::exit(EXIT_ERROR);
+    }
 
-    if args.dry_run {
+    if args.dry_run && !args.list_only && args.command.is_none() {
         warn!("Dry run is enabled. No images will be deleted!");
     }
 
-    let start = time::Instant::now();
+    let start = std::time::Instant::now();
+    let shutdown = handle_signals();
 
-    process(args).await.unwrap();
+    let exit_code = match process(args, shutdown.clone()).await {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            error!("{e:#}");
+            EXIT_ERROR
+        }
+    };
+    let exit_code = if *shutdown.borrow() { EXIT_INTERRUPTED } else { exit_code };
 
-    let delta = time::Instant::now() - start;
-    info!("Done. Took {}", fmt_duration(delta));
+    info!("Done. Took {}", fmt_duration(start.elapsed()));
+    std::process::exit(exit_code);
 }
 
-fn fmt_duration(duration: time::Duration) -> String {
-    if duration.whole_seconds() > 0 {
-        format!("{}s", duration.whole_seconds())
-    } else if duration.whole_milliseconds() > 0 {
-        format!("{}ms", duration.whole_milliseconds())
-    } else {
-        format!("{}µs", duration.whole_microseconds())
-    }
-}
+/// Clean up every registry, returning the exit code.
+/// A registry failing doesn't stop the others from being cleaned up, unless `--fail-fast` is set
+async fn process(args: Args, shutdown: watch::Receiver<bool>) -> Result<i32> {
+    // Released when the process exits
+    let _lock = match &args.lock_file {
+        Some(path) => Some(acquire_lock(path, args.lock_wait).await?),
+        None => None,
+    };
+
+    let output = args.output;
+    let output_file = args.output_file.clone();
+    let list_only = args.list_only;
+    let command = args.command.clone();
+    let fail_fast = args.fail_fast;
+    let quiet = args.quiet;
+    let configs = args.into_configs()?;
+    let multiple = configs.len() > 1;
+
+    let mut exit_code = 0;
+    let mut reports = Vec::with_capacity(configs.len());
+    for mut config in configs {
+        if *shutdown.borrow() {
+            break;
+        }
+        config.shutdown = Some(shutdown.clone());
 
-async fn process(args: Args) -> Result<()> {
-    debug!("Collecting repositories");
-    let repositories = list_repositories(&args.registry).await?;
-    debug!("Collecting tags");
-    let tags = collect_tasks(&args.registry, &repositories, list_tags).await?
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>();
-
-    let mut map: HashMap<&Repository, Vec<Tag>> = HashMap::new();
-    for tag in tags {
-        // We do this option shennanigans to avoid cloning
-        // https://users.rust-lang.org/t/how-to-avoid-redundant-cloning-on-hashmap-insertion/23743/4
-        let mut tag_option = Some(tag);
-        let repository = tag_option.as_ref().unwrap().1;
-
-        map.entry(repository)
-            .and_modify(|x| x.push(tag_option.take().unwrap()))
-            .or_insert_with(|| vec![tag_option.unwrap()]);
+        let registry = config.registry.clone();
+        // Group the output by registry
+        let span = if multiple { info_span!("registry", registry) } else { Span::none() };
+
+        match clean(config, list_only, command.as_ref()).instrument(span).await {
+            Ok(report) => {
+                if report.errors() > 0 {
+                    exit_code = exit_code.max(EXIT_FAILED_OPERATIONS);
+                }
+                reports.push(report);
+            },
+            Err(e) if fail_fast || !multiple => return Err(e),
+            Err(e) => {
+                error!("Failed to clean up {registry}: {e:#}");
+                exit_code = EXIT_ERROR;
+            }
+        }
     }
 
-    debug!("Filtering repositories to keep");
-    let to_process_tags = map.into_iter()
-        .filter(|(repository, tags)| {
-            // We count how many 'free' tags there are
-            // If there are no free tags, we don't touch the repository
-            // A free tag is defined as:
-            // - a tag that is not named 'latest'
-            // - a tag of which the name does not start with 'v'
-            // The reason for this distinction is to avoid breaking deployments
-            // which might depend on the latest tag or a specific version
-
-            // This is only ever 0 or 1
-            // We could represent this as a boolean but
-            // we're using it for addition later, so converting
-            // makes no sense
-            let latest_tag = tags.iter()
-                .filter(|x| x.0.eq("latest"))
-                .count();
-
-            let version_tags = tags.iter()
-                .filter(|x| x.0.starts_with("v"))
-                .count();
-
-            let required_tags = 1 + latest_tag + version_tags;
-            if tags.len() > required_tags {
-                debug!("Continueing with Repository {} because it has free tags (it has {} tags, {version_tags} version tags, and {latest_tag} latest tags)", repository.0, tags.len());
-                true
+    // Printed rather than logged, as the log level hides it
+    if quiet && output == OutputFormat::Text {
+        for report in &reports {
+            if multiple {
+                println!("{}: {}", report.registry(), report.summary());
             } else {
-                debug!("Not continueing with Repository {}, because it has no free tags (it has {} tags, {version_tags} version tags, and {latest_tag} latest tags)", repository.0, tags.len());
-                false
+                println!("{}", report.summary());
             }
-        })
-        .map(|(_, tags)| tags)
-        .flatten()
-        .collect::<Vec<_>>();
-
-    debug!("Collecting digests");
-    let digests = collect_tasks(&args.registry, &to_process_tags, get_tag_digest).await?;
-    debug!("Collecting blobs");
-    let blobs = collect_tasks(&args.registry, &digests, get_blob).await?;
-
-    debug!("Filtering tags");
-    let delete_before = (time::OffsetDateTime::now_utc() - time::Duration::seconds(args.retention as i64)).unix_timestamp();
-    let to_delete = blobs.iter()
-        .filter(|x| x.date < delete_before)
-        .collect::<Vec<_>>();
-
-    if args.dry_run {
-        info!("Dry run is enabled. If it were not, the following images would be deleted:");
-        for blob in to_delete {
-            info!("- {}/{} (Age: {})", blob.tag_digest.1.1.0, blob.tag_digest.1.0, fmt_age(blob.date));
         }
-    } else {
-        for blob in to_delete {
-            info!("Deleting image {}/{}", blob.tag_digest.1.1.0, blob.tag_digest.1.0);
-            delete_digest(&args.registry, blob.tag_digest).await?;
+    }
+
+    if output == OutputFormat::Json {
+        // A single report on its own, so the output doesn't change when cleaning up one registry
+        let json = match reports.as_slice() {
+            [report] if !multiple => serde_json::to_string_pretty(report)?,
+            reports => serde_json::to_string_pretty(reports)?,
+        };
+
+        match &output_file {
+            Some(path) => if let Err(e) = write_report(path, &json) {
+                // The cleanup itself went through, so this is reported separately
+                error!("{e:#}");
+                return Ok(EXIT_REPORT_FAILED);
+            },
+            None => println!("{json}"),
         }
     }
 
-    Ok(())
+    Ok(exit_code)
 }
 
-fn fmt_age(epoch: i64) -> String {
-    let age = time::OffsetDateTime::now_utc().unix_timestamp() - epoch;
+/// Request a shutdown on the first SIGINT or SIGTERM, so deletions in flight can finish.
+/// The second one exits immediately
+fn handle_signals() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        warn!("Shutting down, waiting for deletions in progress to finish. Interrupt again to exit immediately");
+        sender.send_replace(true);
 
-    if age > 86400 {
-        format!("{} Days", age / 86400)
-    } else if age > 3600 {
-        format!("{} Hours", age / 3600)
-    } else if age > 60 {
-        format!("{} Minutes", age / 60)
-    } else {
-        format!("{age} Seconds")
+        shutdown_signal().await;
+        std::process::exit(EXIT_INTERRUPTED);
+    });
+    receiver
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Installing SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
     }
 }
 
-async fn collect_tasks<'a, 'b, I, O, F>(registry: &'b str, input: &'a [I], applied: fn(&'b str, &'a I) -> F) -> Result<Vec<O>>
-where
-    I: 'a,
-    O: 'a,
-    F: Future<Output = Result<O>>,
-{
-    let tasks = input
-        .iter()
-        .map(|x| applied(registry, x))
-        .collect::<Vec<_>>();
-    let collected = join_all(tasks)
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>>>();
-    collected
+/// Take an exclusive lock on `path`, waiting for another run to release it if `wait` is set
+async fn acquire_lock(path: &Path, wait: bool) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        // Another run may hold the lock, so the file is left as it is
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+
+    match file.try_lock() {
+        Ok(()) => return Ok(file),
+        Err(TryLockError::WouldBlock) if wait => info!("Waiting for another run to release the lock on {}", path.display()),
+        Err(TryLockError::WouldBlock) => bail!("Another run holds the lock on {}", path.display()),
+        Err(TryLockError::Error(e)) => return Err(e).with_context(|| format!("Failed to lock {}", path.display())),
+    }
+
+    // Blocking, so it is done off the async runtime
+    let file = tokio::task::spawn_blocking(move || file.lock().map(|_| file)).await?
+        .with_context(|| format!("Failed to lock {}", path.display()))?;
+    Ok(file)
+}
+
+fn write_report(path: &Path, json: &str) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).with_context(|| format!("Failed to create directory {} for the report", parent.display()))?;
+    }
+    std::fs::write(path, json).with_context(|| format!("Failed to write report to {}", path.display()))
 }
 
-fn configure_tracing(debug: bool, trace: bool) {
-    let level = if trace {
+/// The report of a run against a single registry
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Report {
+    Deletion(DeletionReport),
+    Inventory(Inventory),
+    Check(HealthCheck),
+}
+
+impl Report {
+    fn errors(&self) -> usize {
+        match self {
+            Self::Deletion(report) => report.errors,
+            Self::Inventory(inventory) => inventory.errors,
+            Self::Check(check) => check.failed(),
+        }
+    }
+
+    fn registry(&self) -> &str {
+        match self {
+            Self::Deletion(report) => &report.registry,
+            Self::Inventory(inventory) => &inventory.registry,
+            Self::Check(check) => &check.registry,
+        }
+    }
+
+    /// One line summary for `--quiet`
+    fn summary(&self) -> String {
+        let summary = match self {
+            Self::Deletion(report) => {
+                let reclaimed = fmt_bytes(report.images.iter().map(|x| x.size_bytes).sum());
+                let verb = if report.dry_run { "Would reclaim" } else { "Reclaimed" };
+                let interrupted = if report.interrupted { " before being interrupted" } else { "" };
+                format!("{verb} {reclaimed} across {} images in {} repositories{interrupted}", report.images.len(), report.repositories)
+            },
+            Self::Inventory(inventory) => {
+                let tags = inventory.repositories.iter().map(|x| x.tags.len()).sum::<usize>();
+                format!("Listed {tags} tags in {} repositories", inventory.repositories.len())
+            },
+            Self::Check(check) => format!("{} of {} checks passed", check.checks.len() - check.failed(), check.checks.len()),
+        };
+
+        match self.errors() {
+            0 => summary,
+            errors => format!("{summary}, {errors} failed"),
+        }
+    }
+}
+
+async fn clean(config: Config, list_only: bool, command: Option<&Command>) -> Result<Report> {
+    let drc = Drc::new(config)?;
+    match command {
+        Some(Command::Check) => Ok(Report::Check(drc.check().await?)),
+        None if list_only => Ok(Report::Inventory(drc.inventory().await?)),
+        None => Ok(Report::Deletion(drc.run().await?)),
+    }
+}
+
+fn fmt_duration(duration: std::time::Duration) -> String {
+    if duration.as_secs() > 0 {
+        format!("{}s", duration.as_secs())
+    } else if duration.as_millis() > 0 {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{}µs", duration.as_micros())
+    }
+}
+
+fn configure_tracing(args: &Args) -> Result<()> {
+    let color = args.color();
+    let level = if args.trace || args.verbose_http {
         Level::TRACE
-    } else if debug {
+    } else if args.debug {
         Level::DEBUG
+    } else if args.quiet {
+        Level::WARN
     } else {
         Level::INFO
     };
 
-    let subscriber = tracing_subscriber::fmt()
-        .compact()
-        .with_max_level(level)
-        .finish();
+    // Logs go to stderr, so stdout only contains the report
+    let writer = match &args.log_file {
+        Some(path) => BoxMakeWriter::new(std::io::stderr.and(log_file_appender(path, args.log_rotation)?)),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_max_level(level);
+    // Each format is a different type, so they're boxed to pick one at runtime
+    let subscriber: Box<dyn tracing::Subscriber + Send + Sync> = match args.log_format {
+        LogFormat::Compact => Box::new(builder.compact()
+            .with_ansi(color)
+            // Otherwise the colors of our own output are escaped
+            .with_ansi_sanitization(!color)
+            .finish()),
+        LogFormat::Pretty => Box::new(builder.pretty()
+            .with_ansi(color)
+            .with_ansi_sanitization(!color)
+            .finish()),
+        LogFormat::Json => Box::new(builder.json()
+            .with_ansi(false)
+            .finish()),
+    };
     tracing::subscriber::set_global_default(subscriber).expect("Setting tracing subscriber");
+    Ok(())
+}
+
+/// Appender writing to `path`, or with rotation, to `path` with the date appended
+fn log_file_appender(path: &Path, rotation: LogRotation) -> Result<RollingFileAppender> {
+    let rotation = match rotation {
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let name = path.file_name()
+        .with_context(|| format!("Log file {} is not a file name", path.display()))?;
+    let directory = path.parent()
+        .filter(|x| !x.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name.to_string_lossy())
+        .build(directory)
+        .with_context(|| format!("Failed to open log file {}", path.display()))
 }
@@ -0,0 +1,40 @@
+use std::fmt::Write as _;
+use std::path::Path;
+use anyhow::{Context, Result};
+use crate::report::DeletionReport;
+
+/// Render the report in the Prometheus text exposition format,
+/// for use with e.g. the node_exporter textfile collector
+pub fn render_metrics(report: &DeletionReport, duration: std::time::Duration) -> String {
+    let reclaimed = report.images.iter().map(|x| x.size_bytes).sum::<u64>();
+    let labels = format!("{{dry_run=\"{}\"}}", report.dry_run);
+
+    let metrics: [(&str, &str, f64); 7] = [
+        ("drc_repositories_scanned", "Number of repositories scanned", report.repositories as f64),
+        ("drc_tags_examined", "Number of tags examined", report.tags as f64),
+        ("drc_images_deleted", "Number of images deleted, or that would have been deleted in a dry run", report.images.len() as f64),
+        ("drc_bytes_reclaimed", "Estimated number of bytes reclaimed, or that would have been reclaimed in a dry run", reclaimed as f64),
+        ("drc_errors", "Number of operations that failed", report.errors as f64),
+        ("drc_run_duration_seconds", "Duration of the run in seconds", duration.as_secs_f64()),
+        ("drc_last_run_timestamp_seconds", "Unix timestamp at which the run finished", time::OffsetDateTime::now_utc().unix_timestamp() as f64),
+    ];
+
+    let mut output = String::new();
+    for (name, help, value) in metrics {
+        // Writing to a String can't fail
+        let _ = writeln!(output, "# HELP {name} {help}");
+        let _ = writeln!(output, "# TYPE {name} gauge");
+        let _ = writeln!(output, "{name}{labels} {value}");
+    }
+    output
+}
+
+/// Write the metrics file, replacing it atomically so a scraper never sees a partial file
+pub fn write_metrics(path: &Path, report: &DeletionReport, duration: std::time::Duration) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    std::fs::write(&tmp, render_metrics(report, duration)).with_context(|| format!("Failed to write metrics file {}", path.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to write metrics file {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::{Context, Result};
+use glob::Pattern;
+use serde::Deserialize;
+use time::Duration;
+use crate::duration::parse_duration;
+
+/// Retention rules loaded from a policy file, e.g.
+/// ```yaml
+/// repositories:
+///   myapp/db: 90d
+///   myapp/*: 1d
+/// tags:
+///   pr-*: 3d
+///   release-*: forever
+/// ```
+/// A tag rule takes precedence over a repository rule, which takes precedence over `--retention`.
+/// Among rules of the same kind, the most specific pattern wins
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Sorted from most to least specific
+    repositories: Vec<(Pattern, Retention)>,
+    /// Sorted from most to least specific
+    tags: Vec<(Pattern, Retention)>,
+}
+
+/// How long a policy rule keeps images for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    For(Duration),
+    /// Written as `forever`, the images are never deleted due to their age
+    Forever,
+}
+
+impl Retention {
+    fn parse(input: &str) -> Result<Self> {
+        match input {
+            "forever" => Ok(Self::Forever),
+            input => Ok(Self::For(parse_duration(input)?)),
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read policy file {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("Failed to parse policy file {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        #[derive(Debug, Deserialize)]
+        struct PolicyFile {
+            #[serde(default)]
+            repositories: BTreeMap<String, String>,
+            #[serde(default)]
+            tags: BTreeMap<String, String>,
+        }
+
+        let file: PolicyFile = serde_yaml::from_str(contents)?;
+        Ok(Self {
+            repositories: parse_rules(file.repositories, "repository")?,
+            tags: parse_rules(file.tags, "tag")?,
+        })
+    }
+
+    /// The retention of the most specific tag rule matching `tag`, or if there is none, of the most
+    /// specific repository rule matching the repository. Untagged manifests only have repository rules
+    pub fn retention_for(&self, repository: &str, tag: Option<&str>) -> Option<Retention> {
+        let matching = |rules: &[(Pattern, Retention)], name: &str| rules.iter()
+            .find(|(pattern, _)| pattern.matches(name))
+            .map(|(_, retention)| *retention);
+
+        tag.and_then(|tag| matching(&self.tags, tag))
+            .or_else(|| matching(&self.repositories, repository))
+    }
+}
+
+/// Parse rules mapping a pattern to a retention, sorted from most to least specific
+fn parse_rules(rules: BTreeMap<String, String>, kind: &str) -> Result<Vec<(Pattern, Retention)>> {
+    let mut rules = rules.into_iter()
+        .map(|(pattern, retention)| {
+            let retention = Retention::parse(&retention).with_context(|| format!("Invalid retention for {kind} pattern '{pattern}'"))?;
+            let pattern = Pattern::new(&pattern).with_context(|| format!("Invalid {kind} pattern '{pattern}'"))?;
+            Ok((pattern, retention))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    rules.sort_by_key(|(pattern, _)| std::cmp::Reverse(specificity(pattern)));
+    Ok(rules)
+}
+
+/// How specific a pattern is, measured by the number of literal characters in it.
+/// An exact name is therefore always more specific than a wildcard pattern matching the same name
+fn specificity(pattern: &Pattern) -> usize {
+    let mut literal = 0;
+    let mut in_class = false;
+    for c in pattern.as_str().chars() {
+        match c {
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '*' | '?' => {},
+            _ if !in_class => literal += 1,
+            _ => {},
+        }
+    }
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::DAY;
+
+    #[test]
+    fn specificity_counts_literals() {
+        let specificity = |pattern: &str| specificity(&Pattern::new(pattern).unwrap());
+        assert_eq!(specificity("myapp/db"), 8);
+        assert_eq!(specificity("myapp/*"), 6);
+        assert_eq!(specificity("myapp/d?"), 7);
+        assert_eq!(specificity("myapp/[abc]b"), 7);
+        assert_eq!(specificity("*"), 0);
+    }
+
+    #[test]
+    fn most_specific_repository_pattern_wins() {
+        let policy = Policy::parse("
+            repositories:
+              '*': 30d
+              myapp/*: 1d
+              myapp/db: 90d
+              myapp/db-*: 7d
+        ").unwrap();
+
+        assert_eq!(policy.retention_for("myapp/db", None), Some(Retention::For(DAY * 90)));
+        assert_eq!(policy.retention_for("myapp/db-replica", None), Some(Retention::For(DAY * 7)));
+        assert_eq!(policy.retention_for("myapp/ci-cache", None), Some(Retention::For(DAY)));
+        assert_eq!(policy.retention_for("other", None), Some(Retention::For(DAY * 30)));
+    }
+
+    #[test]
+    fn no_matching_repository_pattern() {
+        let policy = Policy::parse("
+            repositories:
+              myapp/*: 1d
+        ").unwrap();
+
+        // Falls back to --retention
+        assert_eq!(policy.retention_for("other/app", None), None);
+        assert_eq!(Policy::default().retention_for("myapp/db", Some("latest")), None);
+    }
+
+    #[test]
+    fn tag_rules_before_repository_rules() {
+        let policy = Policy::parse("
+            repositories:
+              myapp/*: 30d
+            tags:
+              pr-*: 3d
+              pr-keep-*: forever
+              release-*: forever
+        ").unwrap();
+
+        assert_eq!(policy.retention_for("myapp/api", Some("pr-12")), Some(Retention::For(DAY * 3)));
+        assert_eq!(policy.retention_for("myapp/api", Some("pr-keep-12")), Some(Retention::Forever));
+        assert_eq!(policy.retention_for("myapp/api", Some("release-1.0")), Some(Retention::Forever));
+        // Tags without a tag rule fall back to the repository rule
+        assert_eq!(policy.retention_for("myapp/api", Some("latest")), Some(Retention::For(DAY * 30)));
+        // Untagged manifests only have repository rules
+        assert_eq!(policy.retention_for("myapp/api", None), Some(Retention::For(DAY * 30)));
+        // Tag rules apply in every repository, even those without a repository rule
+        assert_eq!(policy.retention_for("other", Some("pr-12")), Some(Retention::For(DAY * 3)));
+        assert_eq!(policy.retention_for("other", Some("latest")), None);
+    }
+
+    #[test]
+    fn invalid_retention() {
+        let error = Policy::parse("
+            tags:
+              pr-*: someday
+        ").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid retention for tag pattern 'pr-*'");
+    }
+}
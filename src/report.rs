@@ -0,0 +1,288 @@
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fmt;
+use serde::Serialize;
+use crate::docker::{Blob, Repository};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable log lines
+    Text,
+    /// A JSON report on stdout
+    Json,
+}
+
+/// Format of the log lines on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One line per event
+    Compact,
+    /// Multiple lines per event, with the fields and source location on their own lines
+    Pretty,
+    /// One JSON object per line, for log collectors like Loki or Elasticsearch
+    Json,
+}
+
+/// How often the `--log-file` is rotated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogRotation {
+    /// A new file every day, with the date appended to the name
+    Daily,
+    /// A new file every hour, with the date and hour appended to the name
+    Hourly,
+    /// A single file, which is appended to
+    Never,
+}
+
+/// The order images are deleted in, which matters when a run is interrupted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DeleteOrder {
+    OldestFirst,
+    /// Frees the most space early on, e.g. when a registry is running out of disk
+    LargestFirst,
+}
+
+impl DeleteOrder {
+    pub fn sort(self, images: &mut [&ImageReport]) {
+        match self {
+            Self::OldestFirst => images.sort_by_key(|x| x.date),
+            Self::LargestFirst => images.sort_by_key(|x| std::cmp::Reverse(x.size_bytes)),
+        }
+    }
+}
+
+/// When progress of long running steps is logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Progress {
+    /// Only when stderr is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// Colors of the human readable output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// Images which are kept or skipped
+    Green,
+    /// Images which would be deleted in a dry run
+    Yellow,
+    /// Images which were deleted
+    Red,
+}
+
+impl Color {
+    /// Wrap `text` in the ANSI escape codes of this color, or return it as is if `enabled` is false
+    pub fn paint(self, text: impl fmt::Display, enabled: bool) -> String {
+        if !enabled {
+            return text.to_string();
+        }
+
+        let code = match self {
+            Self::Green => 32,
+            Self::Yellow => 33,
+            Self::Red => 31,
+        };
+        format!("\x1b[{code}m{text}\x1b[0m")
+    }
+}
+
+/// Machine readable report of a run
+#[derive(Debug, Serialize)]
+pub struct DeletionReport {
+    /// The registry which was cleaned up
+    pub registry: String,
+    pub dry_run: bool,
+    /// The number of repositories scanned, after filtering
+    pub repositories: usize,
+    /// The number of tags examined
+    pub tags: usize,
+    pub images: Vec<ImageReport>,
+    /// Totals per repository, the repositories with the most reclaimed space first
+    pub repository_summaries: Vec<RepositorySummary>,
+    /// The number of operations that failed
+    pub errors: usize,
+    /// Whether the run was interrupted before all planned deletions were started
+    pub interrupted: bool,
+    /// Repositories with more tags than `--warn-tag-count-threshold`, the most tags first
+    pub tag_count_warnings: Vec<TagCountWarning>,
+    /// Repositories which were skipped because planning them took longer than `--repo-timeout-secs`
+    pub abandoned_repositories: Vec<String>,
+    /// Images of a dry run with `--dry-run-validate` which the registry wouldn't let us delete
+    pub undeletable: Vec<UndeletableImage>,
+    /// With `--catalog-limit`, the last repository of this slice of the catalog, which the next run continues after.
+    /// `None` once the end of the catalog is reached
+    pub catalog_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagCountWarning {
+    pub repository: String,
+    pub tags: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UndeletableImage {
+    pub repository: String,
+    /// `None` for untagged manifests
+    pub tag: Option<String>,
+    pub digest: String,
+    pub reason: String,
+}
+
+/// Whether the registry can be reached and the credentials work, see [crate::Drc::check]
+#[derive(Debug, Serialize)]
+pub struct HealthCheck {
+    pub registry: String,
+    /// Whether credentials were configured, either given or from ECR or the Docker config
+    pub credentials: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+impl HealthCheck {
+    /// The number of checks which failed
+    pub fn failed(&self) -> usize {
+        self.checks.iter().filter(|x| !x.passed).count()
+    }
+
+    /// Record the outcome of a check, with the details as either the success or the error
+    pub fn push(&mut self, check: &str, result: Result<String, String>) {
+        let (passed, message) = match result {
+            Ok(message) => (true, message),
+            Err(message) => (false, message),
+        };
+        self.checks.push(CheckResult {
+            check: check.to_string(),
+            passed,
+            message,
+        });
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    /// What was checked, e.g. `api`
+    pub check: String,
+    pub passed: bool,
+    /// Details on what was found, or why the check failed
+    pub message: String,
+}
+
+/// Every tag in a registry, see [crate::Drc::inventory]
+#[derive(Debug, Serialize)]
+pub struct Inventory {
+    pub registry: String,
+    pub repositories: Vec<RepositoryInventory>,
+    /// The number of operations that failed
+    pub errors: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RepositoryInventory {
+    pub repository: String,
+    /// The oldest tags first
+    pub tags: Vec<TagInventory>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagInventory {
+    pub tag: String,
+    pub digest: String,
+    /// `None` if the date of the image couldn't be determined
+    pub age_seconds: Option<i64>,
+    pub size_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RepositorySummary {
+    pub repository: String,
+    /// The number of tags in the repository before the run
+    pub tags: usize,
+    pub kept: usize,
+    /// The number of tags deleted, or in a dry run, that would have been
+    pub deleted: usize,
+    /// Upper bound of the space reclaimed, as layers shared between images are counted for each image
+    pub reclaimed_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageReport {
+    pub repository: String,
+    /// `None` for untagged manifests
+    pub tag: Option<String>,
+    pub digest: String,
+    pub age_seconds: i64,
+    /// Unix timestamp the age is computed from
+    #[serde(skip)]
+    pub date: i64,
+    pub size_bytes: u64,
+    pub status: ImageStatus,
+    /// The other tags pointing at the same manifest, which deleting the manifest removes as well
+    pub aliases: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageStatus {
+    Deleted,
+    /// The image would have been deleted, if this wasn't a dry run
+    WouldDelete,
+}
+
+impl fmt::Display for ImageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.tag {
+            Some(tag) => write!(f, "{}:{}@{}", self.repository, tag, self.digest),
+            None => write!(f, "{}@{}", self.repository, self.digest),
+        }
+    }
+}
+
+impl ImageReport {
+    pub fn new(blob: &Blob, now: i64, status: ImageStatus) -> Self {
+        Self {
+            repository: blob.tag_digest.tag.1.0.clone(),
+            tag: Some(blob.tag_digest.tag.0.clone()),
+            digest: blob.tag_digest.digest.clone(),
+            age_seconds: now - blob.date,
+            date: blob.date,
+            size_bytes: blob.size,
+            status,
+            aliases: Vec::new(),
+        }
+    }
+
+    /// The tag, or the digest for untagged manifests
+    pub fn name(&self) -> &str {
+        self.tag.as_deref().unwrap_or(&self.digest)
+    }
+}
+
+impl DeletionReport {
+    /// Fill in [DeletionReport::repository_summaries] from the images, given the number of tags in each repository
+    pub fn summarize(&mut self, tag_counts: &HashMap<&Repository, usize>) {
+        let mut summaries = tag_counts.iter()
+            .map(|(repository, tags)| (repository.0.as_str(), RepositorySummary {
+                repository: repository.0.clone(),
+                tags: *tags,
+                kept: *tags,
+                deleted: 0,
+                reclaimed_bytes: 0,
+            }))
+            .collect::<HashMap<_, _>>();
+
+        for image in &self.images {
+            if let Some(summary) = summaries.get_mut(image.repository.as_str()) {
+                // Untagged manifests aren't part of the tag count
+                if image.tag.is_some() {
+                    summary.kept -= 1;
+                }
+                summary.deleted += 1;
+                summary.reclaimed_bytes += image.size_bytes;
+            }
+        }
+
+        self.repository_summaries = summaries.into_values().collect();
+        self.repository_summaries.sort_by(|a, b| b.reclaimed_bytes.cmp(&a.reclaimed_bytes).then_with(|| a.repository.cmp(&b.repository)));
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use reqwest::Client;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use crate::docker::{AgeSource, Credentials, DateParseErrors, DeleteMode, Registry, RegistryOptions};
+
+#[derive(Debug, Clone)]
+pub struct StubRequest {
+    pub method: String,
+    /// The path including the query, e.g. `/v2/_catalog?n=2`
+    pub path: String,
+    /// The headers, with lowercase names
+    pub headers: Vec<(String, String)>,
+}
+
+impl StubRequest {
+    /// The path without the query
+    pub fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    /// The values of a query parameter, in order
+    pub fn query(&self, name: &str) -> Vec<String> {
+        let url = url::Url::parse(&format!("http://stub{}", self.path)).unwrap();
+        url.query_pairs()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .collect()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct StubResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    delay: Duration,
+}
+
+impl StubResponse {
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn json(body: serde_json::Value) -> Self {
+        Self::status(200).body("application/json", body.to_string())
+    }
+
+    pub fn status_code(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn body(mut self, content_type: &str, body: String) -> Self {
+        self.body = body;
+        self.header("Content-Type", content_type)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Respond only after `delay`, like a slow registry
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A minimal HTTP server standing in for a registry in tests
+pub struct StubServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+}
+
+impl StubServer {
+    /// Serve the responses of `handler` on a random port until the test ends
+    pub async fn start(handler: impl Fn(&StubRequest) -> StubResponse + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let handler = Arc::new(handler);
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let request = match read_request(&mut stream).await {
+                        Some(request) => request,
+                        None => return,
+                    };
+                    recorded.lock().unwrap().push(request.clone());
+
+                    let response = handler(&request);
+                    tokio::time::sleep(response.delay).await;
+                    let mut head = format!("HTTP/1.1 {} Stub\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len());
+                    for (name, value) in &response.headers {
+                        head.push_str(&format!("{name}: {value}\r\n"));
+                    }
+                    head.push_str("\r\n");
+                    if request.method != "HEAD" {
+                        head.push_str(&response.body);
+                    }
+                    let _ = stream.write_all(head.as_bytes()).await;
+                });
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// The requests received so far, in order
+    pub fn requests(&self) -> Vec<StubRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// A registry client for the server, without retries
+    pub fn registry(&self, options: RegistryOptions) -> Registry {
+        Registry::new(&self.url, Client::new(), None, options).unwrap()
+    }
+
+    /// A registry client for the server, logging in with `credentials`
+    pub fn registry_with(&self, credentials: Credentials, options: RegistryOptions) -> Registry {
+        Registry::new(&self.url, Client::new(), Some(credentials), options).unwrap()
+    }
+}
+
+/// Read the request line and headers. Requests to a registry don't have a body, except for ones we don't send
+async fn read_request(stream: &mut tokio::net::TcpStream) -> Option<StubRequest> {
+    let mut buffer = Vec::new();
+    while !buffer.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        if stream.read(&mut byte).await.ok()? == 0 {
+            return None;
+        }
+        buffer.push(byte[0]);
+    }
+
+    let text = String::from_utf8(buffer).ok()?;
+    let mut lines = text.lines();
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|x| x.split_once(':'))
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    Some(StubRequest { method, path, headers })
+}
+
+/// Options for a registry client in tests, which fails right away rather than retrying
+pub fn options() -> RegistryOptions {
+    RegistryOptions {
+        page_size: None,
+        max_retries: 0,
+        retry_base_delay: Duration::from_millis(1),
+        retry_budget: None,
+        catalog_timeout: None,
+        age_source: AgeSource::Created,
+        delete_mode: DeleteMode::Manifest,
+        rate_limit: None,
+        ecr_region: None,
+        verbose_http: false,
+        delete_referrers: false,
+        manifest_accept: Vec::new(),
+        warmup: None,
+        max_in_flight: 16,
+        max_response_bytes: 32 * 1024 * 1024,
+        platforms: Vec::new(),
+        on_parse_error: DateParseErrors::Skip,
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use reqwest::Client;
+use serde::Serialize;
+use crate::fmt_bytes;
+use crate::report::DeletionReport;
+
+/// Payload posted to the webhook. The `text` field makes it compatible with Slack incoming webhooks,
+/// the other fields are there for anything else consuming it
+#[derive(Debug, Serialize)]
+struct Payload {
+    text: String,
+    registry: String,
+    dry_run: bool,
+    repositories: usize,
+    tags: usize,
+    images: usize,
+    bytes_reclaimed: u64,
+    errors: usize,
+    duration_seconds: f64,
+}
+
+pub async fn notify(client: &Client, url: &str, report: &DeletionReport, duration: std::time::Duration) -> Result<()> {
+    let bytes_reclaimed = report.images.iter().map(|x| x.size_bytes).sum::<u64>();
+    let action = if report.dry_run { "would delete" } else { "deleted" };
+    let text = format!(
+        "Docker registry cleanup of {}{}: {action} {} images ({}) across {} repositories, with {} errors. Took {}s",
+        report.registry,
+        if report.dry_run { " (dry run)" } else { "" },
+        report.images.len(),
+        fmt_bytes(bytes_reclaimed),
+        report.repositories,
+        report.errors,
+        duration.as_secs(),
+    );
+
+    let payload = Payload {
+        text,
+        registry: report.registry.clone(),
+        dry_run: report.dry_run,
+        repositories: report.repositories,
+        tags: report.tags,
+        images: report.images.len(),
+        bytes_reclaimed,
+        errors: report.errors,
+        duration_seconds: duration.as_secs_f64(),
+    };
+
+    client.post(url)
+        .json(&payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}